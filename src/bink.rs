@@ -0,0 +1,258 @@
+//! Parsing of Bink and Bink 2 video headers.
+//!
+//! Both versions start with a little-endian header made up of a magic whose
+//! last byte is the revision, the file size and the video's dimensions,
+//! frame rate and audio track count.
+
+use crate::errors::FormatError;
+use byteorder::{LittleEndian, ReadBytesExt};
+use std::fmt;
+use std::io::{Cursor, Read};
+
+/// Bink 2 magics start with this and are followed by a lowercase revision
+const BINK2_PREFIX: &[u8] = b"KB2";
+/// Known Bink 1 magics (the prefix `BIK` followed by the revision) and the
+/// Bink 2 prefix
+pub const SIGNATURES: [&[u8]; 7] = [
+    b"BIKb",
+    b"BIKd",
+    b"BIKf",
+    b"BIKg",
+    b"BIKh",
+    b"BIKi",
+    BINK2_PREFIX,
+];
+pub const HEADER_SIZE: usize = 0x2C;
+/// Bytes before the file size field that it doesn't include
+const FILE_SIZE_BIAS: u64 = 0x8;
+/// Sanity limits on the header fields
+const MAX_DIMENSION: u32 = 0x2000;
+const MAX_AUDIO_TRACKS: u32 = 0x100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinkVersion {
+    Bink1(char),
+    Bink2(char),
+}
+
+impl BinkVersion {
+    fn from_magic(magic: &[u8; 4]) -> Option<BinkVersion> {
+        let revision = magic[3] as char;
+        if magic.starts_with(BINK2_PREFIX) {
+            if revision.is_ascii_lowercase() {
+                Some(BinkVersion::Bink2(revision))
+            } else {
+                None
+            }
+        } else if SIGNATURES.contains(&&magic[..]) {
+            Some(BinkVersion::Bink1(revision))
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for BinkVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BinkVersion::Bink1(revision) => write!(f, "Bink revision {}", revision),
+            BinkVersion::Bink2(revision) => write!(f, "Bink 2 revision {}", revision),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BinkHeader {
+    version: BinkVersion,
+    file_size: u64,
+    frame_count: u32,
+    largest_frame_size: u32,
+    width: u32,
+    height: u32,
+    fps_dividend: u32,
+    fps_divider: u32,
+    video_flags: u32,
+    audio_track_count: u32,
+}
+
+impl BinkHeader {
+    /// Parses the header at the start of `data` and checks that its fields are
+    /// plausible
+    pub fn parse(data: &[u8]) -> Result<BinkHeader, FormatError> {
+        let mut cursor = Cursor::new(data);
+
+        let mut magic = [0u8; 4];
+        cursor.read_exact(&mut magic)?;
+        let version = BinkVersion::from_magic(&magic).ok_or(FormatError::InvalidMagic {
+            format: "Bink",
+            magic: u32::from_be_bytes(magic),
+        })?;
+
+        let file_size = cursor.read_u32::<LittleEndian>()? as u64 + FILE_SIZE_BIAS;
+        let frame_count = cursor.read_u32::<LittleEndian>()?;
+        let largest_frame_size = cursor.read_u32::<LittleEndian>()?;
+        let _frame_count = cursor.read_u32::<LittleEndian>()?;
+        let width = cursor.read_u32::<LittleEndian>()?;
+        let height = cursor.read_u32::<LittleEndian>()?;
+        let fps_dividend = cursor.read_u32::<LittleEndian>()?;
+        let fps_divider = cursor.read_u32::<LittleEndian>()?;
+        let video_flags = cursor.read_u32::<LittleEndian>()?;
+        let audio_track_count = cursor.read_u32::<LittleEndian>()?;
+
+        let invalid = |reason| FormatError::InvalidHeader {
+            format: "Bink",
+            reason,
+        };
+
+        if frame_count == 0 {
+            return Err(invalid("video has no frames"));
+        }
+
+        if width == 0 || height == 0 || width > MAX_DIMENSION || height > MAX_DIMENSION {
+            return Err(invalid("video dimensions are out of range"));
+        }
+
+        if fps_dividend == 0 || fps_divider == 0 {
+            return Err(invalid("frame rate is zero"));
+        }
+
+        if largest_frame_size as u64 > file_size {
+            return Err(invalid("largest frame is bigger than the file"));
+        }
+
+        if audio_track_count > MAX_AUDIO_TRACKS {
+            return Err(invalid("too many audio tracks"));
+        }
+
+        Ok(BinkHeader {
+            version,
+            file_size,
+            frame_count,
+            largest_frame_size,
+            width,
+            height,
+            fps_dividend,
+            fps_divider,
+            video_flags,
+            audio_track_count,
+        })
+    }
+
+    pub fn version(&self) -> BinkVersion {
+        self.version
+    }
+
+    /// Size of the whole file, including the header
+    pub fn file_size(&self) -> u64 {
+        self.file_size
+    }
+
+    pub fn frame_count(&self) -> u32 {
+        self.frame_count
+    }
+
+    pub fn largest_frame_size(&self) -> u32 {
+        self.largest_frame_size
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Frames per second as a fraction of (dividend, divider)
+    pub fn fps(&self) -> (u32, u32) {
+        (self.fps_dividend, self.fps_divider)
+    }
+
+    pub fn video_flags(&self) -> u32 {
+        self.video_flags
+    }
+
+    pub fn audio_track_count(&self) -> u32 {
+        self.audio_track_count
+    }
+
+    /// Length of the video in seconds
+    pub fn duration(&self) -> f64 {
+        self.frame_count as f64 * self.fps_divider as f64 / self.fps_dividend as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::carvers::BinkCarver;
+    use crate::scanners::Carver;
+
+    /// A 640x480 video at 30000/1001 fps with 300 frames and one audio track.
+    /// The size field holds 0x1000.
+    fn header_bytes(magic: &[u8]) -> Vec<u8> {
+        let mut data = magic.to_vec();
+        for value in [0x1000, 300, 0x800, 300, 640, 480, 30000, 1001, 0, 1].iter() {
+            data.extend(&(*value as u32).to_le_bytes());
+        }
+        data.resize(HEADER_SIZE, 0);
+        data
+    }
+
+    #[test]
+    fn header_is_parsed() {
+        let header = BinkHeader::parse(&header_bytes(b"BIKi")).unwrap();
+
+        assert_eq!(header.version(), BinkVersion::Bink1('i'));
+        assert_eq!(header.frame_count(), 300);
+        assert_eq!(header.largest_frame_size(), 0x800);
+        assert_eq!((header.width(), header.height()), (640, 480));
+        assert_eq!(header.fps(), (30000, 1001));
+        assert_eq!(header.audio_track_count(), 1);
+        assert_eq!(header.duration().round(), 10.0);
+    }
+
+    #[test]
+    fn file_size_includes_magic_and_size() {
+        let header = BinkHeader::parse(&header_bytes(b"BIKi")).unwrap();
+        assert_eq!(header.file_size(), 0x1008);
+    }
+
+    #[test]
+    fn every_revision_is_recognized() {
+        for revision in b"bdfghi".iter() {
+            let magic = [b'B', b'I', b'K', *revision];
+            let header = BinkHeader::parse(&header_bytes(&magic)).unwrap();
+            assert_eq!(header.version(), BinkVersion::Bink1(*revision as char));
+        }
+
+        let header = BinkHeader::parse(&header_bytes(b"KB2j")).unwrap();
+        assert_eq!(header.version(), BinkVersion::Bink2('j'));
+        assert_eq!(header.version().to_string(), "Bink 2 revision j");
+
+        assert!(BinkHeader::parse(&header_bytes(b"BIKa")).is_err());
+        assert!(BinkHeader::parse(&header_bytes(b"KB2J")).is_err());
+    }
+
+    #[test]
+    fn text_is_not_a_video() {
+        let mut text =
+            b"BIKi. The file name of the intro movie, as seen in the game files".to_vec();
+        text.resize(0x100, b' ');
+
+        assert!(!BinkCarver.validate(&text));
+        assert!(BinkHeader::parse(&text).is_err());
+        assert!(BinkCarver.carve(&text, 0).is_none());
+    }
+
+    #[test]
+    fn carved_size_comes_from_header() {
+        let mut data = vec![0; 0x10];
+        data.extend(header_bytes(b"KB2a"));
+        data.resize(0x2000, 0);
+
+        assert!(BinkCarver.validate(&data[0x10..]));
+        let file = BinkCarver.carve(&data, 0x10).unwrap();
+        assert_eq!(file.size(), Some(0x1008));
+    }
+}
@@ -0,0 +1,315 @@
+//! Carvers for archives.
+
+use super::carve_limit;
+use crate::scanners::{CarvedFile, Carver};
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use std::cmp;
+
+const ZIP_LOCAL_FILE_MAGIC: &[u8] = b"PK\x03\x04";
+const ZIP_END_OF_CENTRAL_DIRECTORY_MAGIC: &[u8] = b"PK\x05\x06";
+const ZIP_LOCAL_FILE_HEADER_SIZE: usize = 0x1E;
+const ZIP_END_OF_CENTRAL_DIRECTORY_SIZE: usize = 0x16;
+
+/// Carves ZIP archives up to the end of their central directory. Archives
+/// whose central directory can't be found are carved up to the maximum size.
+pub struct ZipCarver {
+    max_size: u64,
+}
+
+impl ZipCarver {
+    pub fn new(max_size: u64) -> ZipCarver {
+        ZipCarver { max_size }
+    }
+}
+
+impl Carver for ZipCarver {
+    fn name(&self) -> &'static str {
+        "zip"
+    }
+
+    fn extension(&self) -> &'static str {
+        "zip"
+    }
+
+    fn signatures(&self) -> &[&'static [u8]] {
+        &[ZIP_LOCAL_FILE_MAGIC]
+    }
+
+    fn validate(&self, header: &[u8]) -> bool {
+        // Version needed to extract and the length of the file name
+        header.len() >= ZIP_LOCAL_FILE_HEADER_SIZE
+            && LittleEndian::read_u16(&header[0x4..]) <= 0xFF
+            && LittleEndian::read_u16(&header[0x1A..]) > 0
+    }
+
+    fn carve(&self, data: &[u8], offset: usize) -> Option<CarvedFile> {
+        let limit = carve_limit(data, offset, self.max_size);
+
+        // Every file in an archive starts with a local file header, but only
+        // the first one is at the offset that the central directory is
+        // relative to. Archives stored inside of this one have their own end
+        // of central directory records, which are skipped.
+        let search_start = offset + ZIP_LOCAL_FILE_HEADER_SIZE;
+        let records = data.get(search_start..limit).unwrap_or(&[]);
+        let mut end = None;
+        let mut other_archive_end = None;
+        for (i, record) in records
+            .windows(ZIP_END_OF_CENTRAL_DIRECTORY_SIZE)
+            .enumerate()
+            .filter(|(_, record)| record.starts_with(ZIP_END_OF_CENTRAL_DIRECTORY_MAGIC))
+        {
+            let pos = search_start + i;
+            let central_directory_size = LittleEndian::read_u32(&record[0xC..]) as usize;
+            let central_directory_offset = LittleEndian::read_u32(&record[0x10..]) as usize;
+            let comment_len = LittleEndian::read_u16(&record[0x14..]) as usize;
+
+            if pos.checked_sub(central_directory_size) == Some(offset + central_directory_offset) {
+                end = Some(pos + ZIP_END_OF_CENTRAL_DIRECTORY_SIZE + comment_len);
+                break;
+            }
+
+            other_archive_end.get_or_insert(pos);
+        }
+
+        if let (None, Some(other_archive_end)) = (end, other_archive_end) {
+            debug!(
+                "ZIP local file header at 0x{:X} belongs to an archive that ends at 0x{:X}",
+                offset, other_archive_end
+            );
+            return None;
+        }
+
+        let size = match end {
+            Some(end) => (end - offset) as u64,
+            None => (limit - offset) as u64,
+        };
+
+        debug!(
+            "Got ZIP archive at offset 0x{:X} (size: 0x{:X}{})",
+            offset,
+            size,
+            if end.is_some() {
+                ""
+            } else {
+                ", no central directory"
+            }
+        );
+
+        Some(CarvedFile::new(self, offset as u64, Some(size)))
+    }
+}
+
+const XUIZ_MAGIC: &[u8] = b"XUIZ";
+/// Size of the header, which ends with the number of files
+const XUIZ_HEADER_SIZE: usize = 0x16;
+/// Size of a file entry's length, offset and name length
+const XUIZ_ENTRY_SIZE: usize = 0x9;
+
+/// Carves XUI archives (XZP files) using their file table. Archives whose
+/// file table doesn't make sense are carved up to the maximum size.
+pub struct XuizCarver {
+    max_size: u64,
+}
+
+impl XuizCarver {
+    pub fn new(max_size: u64) -> XuizCarver {
+        XuizCarver { max_size }
+    }
+}
+
+/// Works out the length of the XUI archive at the start of `data` from its
+/// file table. Files are stored after the table with offsets relative to the
+/// end of it.
+fn xuiz_file_size(data: &[u8]) -> Option<u64> {
+    let file_count = BigEndian::read_u16(data.get(0x14..XUIZ_HEADER_SIZE)?);
+    if file_count == 0 {
+        return None;
+    }
+
+    let mut pos = XUIZ_HEADER_SIZE;
+    let mut files_end = 0;
+    for _ in 0..file_count {
+        let entry = data.get(pos..pos + XUIZ_ENTRY_SIZE)?;
+        let size = BigEndian::read_u32(entry) as u64;
+        let offset = BigEndian::read_u32(&entry[0x4..]) as u64;
+        let name_len = entry[0x8] as usize;
+
+        // Names are UTF-16 paths
+        let name = data.get(pos + XUIZ_ENTRY_SIZE..pos + XUIZ_ENTRY_SIZE + name_len * 2)?;
+        if name_len == 0 || name.chunks(2).any(|c| BigEndian::read_u16(c) < 0x20) {
+            return None;
+        }
+
+        files_end = cmp::max(files_end, offset + size);
+        pos += XUIZ_ENTRY_SIZE + name_len * 2;
+    }
+
+    Some(pos as u64 + files_end)
+}
+
+impl Carver for XuizCarver {
+    fn name(&self) -> &'static str {
+        "xuiz"
+    }
+
+    fn extension(&self) -> &'static str {
+        "xzp"
+    }
+
+    fn signatures(&self) -> &[&'static [u8]] {
+        &[XUIZ_MAGIC]
+    }
+
+    fn carve(&self, data: &[u8], offset: usize) -> Option<CarvedFile> {
+        let limit = carve_limit(data, offset, self.max_size);
+
+        let size = match xuiz_file_size(&data[offset..limit]) {
+            Some(size) => size,
+            None => {
+                debug!("XUI archive at 0x{:X} has an invalid file table", offset);
+                (limit - offset) as u64
+            }
+        };
+
+        debug!(
+            "Got XUI archive at offset 0x{:X} (size: 0x{:X})",
+            offset, size
+        );
+
+        Some(CarvedFile::new(self, offset as u64, Some(size)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A ZIP archive holding a single empty file named `a`, with a three byte
+    /// comment
+    fn zip_file() -> Vec<u8> {
+        let mut data = vec![0u8; ZIP_LOCAL_FILE_HEADER_SIZE];
+        data[..4].copy_from_slice(ZIP_LOCAL_FILE_MAGIC);
+        LittleEndian::write_u16(&mut data[0x4..], 20);
+        LittleEndian::write_u16(&mut data[0x1A..], 1);
+        data.push(b'a');
+
+        let central_directory_offset = data.len();
+        let mut central_directory = vec![0u8; 0x2E];
+        central_directory[..4].copy_from_slice(b"PK\x01\x02");
+        central_directory.push(b'a');
+        data.extend(&central_directory);
+
+        let mut end = vec![0u8; ZIP_END_OF_CENTRAL_DIRECTORY_SIZE];
+        end[..4].copy_from_slice(ZIP_END_OF_CENTRAL_DIRECTORY_MAGIC);
+        LittleEndian::write_u32(&mut end[0xC..], central_directory.len() as u32);
+        LittleEndian::write_u32(&mut end[0x10..], central_directory_offset as u32);
+        LittleEndian::write_u16(&mut end[0x14..], 3);
+        data.extend(&end);
+        data.extend(b"abc");
+
+        data
+    }
+
+    /// Surrounds `file` with junk, returning the data and the offset of the
+    /// file inside of it
+    fn embed(file: &[u8]) -> (Vec<u8>, usize) {
+        let mut data = vec![0xAA; 0x10];
+        data.extend(file);
+        data.extend(vec![0xAA; 0x100]);
+        (data, 0x10)
+    }
+
+    #[test]
+    fn zip_size_ends_at_comment() {
+        let zip = zip_file();
+        let (data, offset) = embed(&zip);
+
+        let carver = ZipCarver::new(0x1000);
+        assert!(carver.validate(&data[offset..]));
+        let file = carver.carve(&data, offset).unwrap();
+        assert_eq!(file.size(), Some(zip.len() as u64));
+    }
+
+    #[test]
+    fn zip_without_central_directory_is_capped() {
+        let zip = zip_file();
+        let (data, offset) = embed(&zip[..ZIP_LOCAL_FILE_HEADER_SIZE + 1]);
+
+        let file = ZipCarver::new(0x40).carve(&data, offset).unwrap();
+        assert_eq!(file.size(), Some(0x40));
+    }
+
+    #[test]
+    fn zip_inside_another_archive_is_skipped() {
+        // Start the archive 0x10 bytes before the local file header, which then
+        // doesn't line up with the central directory
+        let mut zip = zip_file();
+        let end = zip.len() - 3 - ZIP_END_OF_CENTRAL_DIRECTORY_SIZE;
+        let central_directory_offset = LittleEndian::read_u32(&zip[end + 0x10..]);
+        LittleEndian::write_u32(&mut zip[end + 0x10..], central_directory_offset + 0x10);
+        zip.splice(0..0, vec![0u8; 0x10]);
+        let (data, offset) = embed(&zip);
+
+        assert!(ZipCarver::new(0x1000).carve(&data, offset + 0x10).is_none());
+    }
+
+    #[test]
+    fn zip_holding_another_archive_ends_at_its_own_central_directory() {
+        let inner = zip_file();
+        let mut zip = zip_file();
+        let end = zip.len() - 3 - ZIP_END_OF_CENTRAL_DIRECTORY_SIZE;
+        let central_directory_offset = LittleEndian::read_u32(&zip[end + 0x10..]);
+        LittleEndian::write_u32(
+            &mut zip[end + 0x10..],
+            central_directory_offset + inner.len() as u32,
+        );
+        let insert_at = central_directory_offset as usize;
+        zip.splice(insert_at..insert_at, inner);
+        let (data, offset) = embed(&zip);
+
+        let file = ZipCarver::new(0x1000).carve(&data, offset).unwrap();
+        assert_eq!(file.size(), Some(zip.len() as u64));
+    }
+
+    /// An XUI archive holding `a` (0x10 bytes) followed by `bc` (0x20 bytes)
+    fn xuiz_file() -> Vec<u8> {
+        let mut data = vec![0u8; XUIZ_HEADER_SIZE];
+        data[..4].copy_from_slice(XUIZ_MAGIC);
+        BigEndian::write_u16(&mut data[0x14..], 2);
+
+        for (size, offset, name) in [(0x10, 0, "a"), (0x20, 0x10, "bc")].iter() {
+            let mut entry = vec![0u8; XUIZ_ENTRY_SIZE];
+            BigEndian::write_u32(&mut entry, *size);
+            BigEndian::write_u32(&mut entry[0x4..], *offset);
+            entry[0x8] = name.len() as u8;
+            data.extend(&entry);
+            for c in name.encode_utf16() {
+                data.extend(&c.to_be_bytes());
+            }
+        }
+
+        data.extend(vec![0x55; 0x30]);
+        data
+    }
+
+    #[test]
+    fn xuiz_size_covers_file_table_and_files() {
+        let xuiz = xuiz_file();
+        assert_eq!(xuiz_file_size(&xuiz), Some(xuiz.len() as u64));
+
+        let (data, offset) = embed(&xuiz);
+        let file = XuizCarver::new(0x1000).carve(&data, offset).unwrap();
+        assert_eq!(file.size(), Some(xuiz.len() as u64));
+    }
+
+    #[test]
+    fn xuiz_with_invalid_file_table_is_capped() {
+        let mut xuiz = xuiz_file();
+        BigEndian::write_u16(&mut xuiz[0x14..], 0);
+        assert_eq!(xuiz_file_size(&xuiz), None);
+
+        let (data, offset) = embed(&xuiz);
+        let file = XuizCarver::new(0x40).carve(&data, offset).unwrap();
+        assert_eq!(file.size(), Some(0x40));
+    }
+}
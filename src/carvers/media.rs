@@ -0,0 +1,660 @@
+//! Carvers for images, textures, audio and video.
+
+use super::carve_limit;
+use crate::scanners::{CarvedFile, Carver};
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use std::cmp;
+
+const PNG_MAGIC: &[u8] = b"\x89PNG\r\n\x1a\n";
+/// Size of a PNG chunk's length, type and CRC
+const PNG_CHUNK_OVERHEAD: usize = 0xC;
+const PNG_IHDR_SIZE: usize = 0xD;
+
+/// Walks the chunks of PNG images up to the IEND chunk
+pub struct PngCarver {
+    max_size: u64,
+}
+
+impl PngCarver {
+    pub fn new(max_size: u64) -> PngCarver {
+        PngCarver { max_size }
+    }
+}
+
+impl Carver for PngCarver {
+    fn name(&self) -> &'static str {
+        "png"
+    }
+
+    fn extension(&self) -> &'static str {
+        "png"
+    }
+
+    fn signatures(&self) -> &[&'static [u8]] {
+        &[PNG_MAGIC]
+    }
+
+    fn validate(&self, header: &[u8]) -> bool {
+        header.len() >= PNG_MAGIC.len() + PNG_CHUNK_OVERHEAD + PNG_IHDR_SIZE
+            && &header[0xC..0x10] == b"IHDR"
+    }
+
+    fn carve(&self, data: &[u8], offset: usize) -> Option<CarvedFile> {
+        let limit = carve_limit(data, offset, self.max_size);
+        let width = BigEndian::read_u32(&data[offset + 0x10..]);
+        let height = BigEndian::read_u32(&data[offset + 0x14..]);
+
+        // A chunk that doesn't look right usually means that the rest of the
+        // image has been overwritten, so keep what was intact
+        let mut end = offset + PNG_MAGIC.len();
+        while end + PNG_CHUNK_OVERHEAD <= limit {
+            let chunk_len = BigEndian::read_u32(&data[end..]) as usize;
+            let chunk_type = &data[end + 4..end + 8];
+            if !chunk_type.iter().all(u8::is_ascii_alphabetic) {
+                break;
+            }
+
+            end = cmp::min(end + PNG_CHUNK_OVERHEAD + chunk_len, limit);
+            if chunk_type == b"IEND" {
+                break;
+            }
+        }
+
+        let size = (end - offset) as u64;
+        debug!(
+            "Got PNG image at offset 0x{:X} ({}x{}, size: 0x{:X})",
+            offset, width, height, size
+        );
+
+        Some(
+            CarvedFile::new(self, offset as u64, Some(size))
+                .with_name(format!("{}x{}_{:X}", width, height, offset)),
+        )
+    }
+}
+
+const JPEG_SOI: &[u8] = &[0xFF, 0xD8, 0xFF];
+const JPEG_EOI: u8 = 0xD9;
+const JPEG_SOS: u8 = 0xDA;
+
+/// Walks the segments of JPEG images up to the end of image marker. JPEGs
+/// don't store their length, so images without one are carved up to the
+/// maximum size.
+pub struct JpegCarver {
+    max_size: u64,
+}
+
+impl JpegCarver {
+    pub fn new(max_size: u64) -> JpegCarver {
+        JpegCarver { max_size }
+    }
+}
+
+/// Whether `marker` is a start of frame marker, which holds the image size
+fn is_jpeg_sof(marker: u8) -> bool {
+    match marker {
+        0xC4 | 0xC8 | 0xCC => false,
+        0xC0..=0xCF => true,
+        _ => false,
+    }
+}
+
+/// Whether `marker` stands on its own without a length
+fn is_jpeg_standalone(marker: u8) -> bool {
+    matches!(marker, 0x01 | 0xD0..=0xD8)
+}
+
+impl Carver for JpegCarver {
+    fn name(&self) -> &'static str {
+        "jpeg"
+    }
+
+    fn extension(&self) -> &'static str {
+        "jpg"
+    }
+
+    fn signatures(&self) -> &[&'static [u8]] {
+        &[JPEG_SOI]
+    }
+
+    fn validate(&self, header: &[u8]) -> bool {
+        // The first segment is an APPn, a quantization or Huffman table, or a
+        // comment
+        matches!(
+            header.get(3),
+            Some(0xE0..=0xEF) | Some(0xDB) | Some(0xC4) | Some(0xFE)
+        )
+    }
+
+    fn carve(&self, data: &[u8], offset: usize) -> Option<CarvedFile> {
+        let limit = carve_limit(data, offset, self.max_size);
+        let mut dimensions = None;
+        let mut end = None;
+
+        let mut pos = offset + 2;
+        while pos + 4 <= limit {
+            if data[pos] != 0xFF {
+                debug!("Invalid JPEG segment at 0x{:X}", pos);
+                break;
+            }
+
+            let marker = data[pos + 1];
+            if marker == 0xFF {
+                // Fill byte
+                pos += 1;
+                continue;
+            }
+
+            if marker == JPEG_EOI {
+                end = Some(pos + 2);
+                break;
+            }
+
+            if is_jpeg_standalone(marker) {
+                pos += 2;
+                continue;
+            }
+
+            let segment_len = BigEndian::read_u16(&data[pos + 2..]) as usize;
+            if segment_len < 2 {
+                break;
+            }
+
+            if is_jpeg_sof(marker) && pos + 9 <= limit {
+                let height = BigEndian::read_u16(&data[pos + 5..]);
+                let width = BigEndian::read_u16(&data[pos + 7..]);
+                dimensions = Some((width, height));
+            }
+
+            pos += 2 + segment_len;
+
+            if marker == JPEG_SOS {
+                // Skip the entropy-coded data. 0xFF bytes in it are followed
+                // by a zero byte or a restart marker.
+                while pos + 1 < limit
+                    && (data[pos] != 0xFF || matches!(data[pos + 1], 0x00 | 0xD0..=0xD7))
+                {
+                    pos += 1;
+                }
+            }
+        }
+
+        let (size, complete) = match end {
+            Some(end) => (end - offset, true),
+            None => (limit - offset, false),
+        };
+        let size = size as u64;
+
+        let name = match dimensions {
+            Some((width, height)) => format!("{}x{}_{:X}", width, height, offset),
+            None => format!("{}_{:X}", self.name(), offset),
+        };
+
+        debug!(
+            "Got JPEG image at offset 0x{:X} ({}, size: 0x{:X}{})",
+            offset,
+            dimensions
+                .map(|(width, height)| format!("{}x{}", width, height))
+                .unwrap_or_else(|| "unknown dimensions".to_owned()),
+            size,
+            if complete { "" } else { ", no end of image" }
+        );
+
+        Some(CarvedFile::new(self, offset as u64, Some(size)).with_name(name))
+    }
+}
+
+const DDS_MAGIC: &[u8] = b"DDS ";
+/// Size of the magic and the DDS_HEADER
+const DDS_HEADER_SIZE: u64 = 0x80;
+const DDSD_MIPMAPCOUNT: u32 = 0x2_0000;
+const DDSPF_ALPHA: u32 = 0x2;
+const DDSPF_FOURCC: u32 = 0x4;
+const DDSPF_RGB: u32 = 0x40;
+const DDSPF_YUV: u32 = 0x200;
+const DDSPF_LUMINANCE: u32 = 0x2_0000;
+const DDSCAPS2_CUBEMAP: u32 = 0x200;
+const DDSCAPS2_CUBEMAP_FACES: u32 = 0xFC00;
+const DDSCAPS2_VOLUME: u32 = 0x20_0000;
+/// Largest texture dimension accepted, which weeds out garbage headers
+const DDS_MAX_DIMENSION: u32 = 0x4000;
+
+/// Works out the size of DDS textures from their dimensions, format and mip
+/// count. Textures in formats that it doesn't know about (such as those with
+/// a DX10 header) are carved up to the maximum size.
+pub struct DdsCarver {
+    max_size: u64,
+}
+
+impl DdsCarver {
+    pub fn new(max_size: u64) -> DdsCarver {
+        DdsCarver { max_size }
+    }
+}
+
+/// Size of a single `width` by `height` surface. Block-compressed formats
+/// store 4x4 blocks of `block_size` bytes, everything else stores
+/// `bits_per_pixel` for every pixel.
+fn dds_surface_size(
+    width: u64,
+    height: u64,
+    block_size: Option<u64>,
+    bits_per_pixel: u64,
+) -> Option<u64> {
+    match block_size {
+        Some(block_size) => cmp::max(1, width.div_ceil(4))
+            .checked_mul(cmp::max(1, height.div_ceil(4)))?
+            .checked_mul(block_size),
+        None => width
+            .checked_mul(bits_per_pixel)?
+            .div_ceil(8)
+            .checked_mul(height),
+    }
+}
+
+/// Works out the length of the DDS file in `header`, if the pixel format is
+/// one that's understood and the size doesn't overflow
+fn dds_file_size(header: &[u8]) -> Option<u64> {
+    let field = |offset: usize| LittleEndian::read_u32(&header[offset..]);
+
+    let flags = field(0x8);
+    let height = field(0xC);
+    let width = field(0x10);
+    let depth = field(0x18);
+    let mip_count = field(0x1C);
+    let pixel_format_flags = field(0x50);
+    let four_cc = &header[0x54..0x58];
+    let bits_per_pixel = field(0x58);
+    let caps2 = field(0x70);
+
+    if width == 0 || height == 0 || width > DDS_MAX_DIMENSION || height > DDS_MAX_DIMENSION {
+        return None;
+    }
+
+    let block_size = if pixel_format_flags & DDSPF_FOURCC != 0 {
+        match four_cc {
+            b"DXT1" | b"ATI1" | b"BC4U" | b"BC4S" => Some(8),
+            b"DXT2" | b"DXT3" | b"DXT4" | b"DXT5" | b"ATI2" | b"BC5U" | b"BC5S" => Some(16),
+            _ => return None,
+        }
+    } else if pixel_format_flags & (DDSPF_RGB | DDSPF_LUMINANCE | DDSPF_ALPHA | DDSPF_YUV) != 0 {
+        if bits_per_pixel == 0 || bits_per_pixel > 128 || bits_per_pixel % 8 != 0 {
+            return None;
+        }
+
+        None
+    } else {
+        return None;
+    };
+
+    let mip_count = if flags & DDSD_MIPMAPCOUNT != 0 {
+        cmp::max(1, mip_count)
+    } else {
+        1
+    };
+    if mip_count > 32 {
+        return None;
+    }
+
+    let faces = if caps2 & DDSCAPS2_CUBEMAP != 0 {
+        (caps2 & DDSCAPS2_CUBEMAP_FACES).count_ones() as u64
+    } else {
+        1
+    };
+    let depth = if caps2 & DDSCAPS2_VOLUME != 0 {
+        if depth > DDS_MAX_DIMENSION {
+            return None;
+        }
+
+        cmp::max(1, depth) as u64
+    } else {
+        1
+    };
+
+    let mut surfaces: u64 = 0;
+    for mip in 0..mip_count {
+        let width = cmp::max(1, width as u64 >> mip);
+        let height = cmp::max(1, height as u64 >> mip);
+        let depth = cmp::max(1, depth >> mip);
+        let size = dds_surface_size(width, height, block_size, bits_per_pixel as u64)?
+            .checked_mul(depth)?;
+        surfaces = surfaces.checked_add(size)?;
+    }
+
+    surfaces.checked_mul(faces)?.checked_add(DDS_HEADER_SIZE)
+}
+
+impl Carver for DdsCarver {
+    fn name(&self) -> &'static str {
+        "dds"
+    }
+
+    fn extension(&self) -> &'static str {
+        "dds"
+    }
+
+    fn signatures(&self) -> &[&'static [u8]] {
+        &[DDS_MAGIC]
+    }
+
+    fn validate(&self, header: &[u8]) -> bool {
+        // Sizes of the DDS_HEADER and its DDS_PIXELFORMAT
+        header.len() >= DDS_HEADER_SIZE as usize
+            && LittleEndian::read_u32(&header[0x4..]) == 0x7C
+            && LittleEndian::read_u32(&header[0x4C..]) == 0x20
+    }
+
+    fn carve(&self, data: &[u8], offset: usize) -> Option<CarvedFile> {
+        let header = &data[offset..];
+        let width = LittleEndian::read_u32(&header[0x10..]);
+        let height = LittleEndian::read_u32(&header[0xC..]);
+
+        let size = match dds_file_size(header) {
+            Some(size) => size,
+            None => {
+                debug!("Unknown DDS format at 0x{:X}", offset);
+                (carve_limit(data, offset, self.max_size) - offset) as u64
+            }
+        };
+
+        debug!(
+            "Got DDS texture at offset 0x{:X} ({}x{}, size: 0x{:X})",
+            offset, width, height, size
+        );
+
+        Some(
+            CarvedFile::new(self, offset as u64, Some(size))
+                .with_name(format!("{}x{}_{:X}", width, height, offset)),
+        )
+    }
+}
+
+/// WAVE format tags used by XMA audio
+const WAVE_FORMAT_XMA: u16 = 0x165;
+const WAVE_FORMAT_XMA2: u16 = 0x166;
+
+/// Carves RIFF files such as WAVE and XMA audio using the length in their
+/// header
+pub struct RiffCarver;
+
+/// Finds the format tag in the `fmt ` chunk of a WAVE file
+fn wave_format_tag(riff: &[u8]) -> Option<u16> {
+    let mut pos = 0xC;
+    while pos + 8 <= riff.len() {
+        let chunk_id = &riff[pos..pos + 4];
+        let chunk_len = LittleEndian::read_u32(&riff[pos + 4..]) as usize;
+        if chunk_id == b"fmt " {
+            return riff.get(pos + 8..pos + 10).map(LittleEndian::read_u16);
+        }
+
+        // Chunks are padded to an even length
+        pos += 8 + chunk_len + (chunk_len & 1);
+    }
+
+    None
+}
+
+impl Carver for RiffCarver {
+    fn name(&self) -> &'static str {
+        "riff"
+    }
+
+    fn extension(&self) -> &'static str {
+        "riff"
+    }
+
+    fn signatures(&self) -> &[&'static [u8]] {
+        &[b"RIFF"]
+    }
+
+    fn validate(&self, header: &[u8]) -> bool {
+        // The form type is a printable four character code
+        header.len() >= 0xC
+            && LittleEndian::read_u32(&header[0x4..]) >= 4
+            && header[0x8..0xC]
+                .iter()
+                .all(|c| c.is_ascii_alphanumeric() || *c == b' ')
+    }
+
+    fn carve(&self, data: &[u8], offset: usize) -> Option<CarvedFile> {
+        let size = LittleEndian::read_u32(&data[offset + 4..]) as u64 + 8;
+        let form_type = &data[offset + 8..offset + 0xC];
+
+        let riff = &data[offset..cmp::min(data.len(), offset + size as usize)];
+        let extension = match form_type {
+            b"WAVE" => match wave_format_tag(riff) {
+                Some(WAVE_FORMAT_XMA) | Some(WAVE_FORMAT_XMA2) => "xma",
+                _ => "wav",
+            },
+            b"XWMA" => "xwm",
+            b"AVI " => "avi",
+            _ => self.extension(),
+        };
+
+        debug!(
+            "Got RIFF {} file at offset 0x{:X} (size: 0x{:X})",
+            String::from_utf8_lossy(form_type).trim_end(),
+            offset,
+            size
+        );
+
+        Some(CarvedFile::new(self, offset as u64, Some(size)).with_extension(extension))
+    }
+}
+
+const ASF_HEADER_GUID: &[u8] = &[
+    0x30, 0x26, 0xB2, 0x75, 0x8E, 0x66, 0xCF, 0x11, 0xA6, 0xD9, 0x00, 0xAA, 0x00, 0x62, 0xCE, 0x6C,
+];
+const ASF_DATA_GUID: &[u8] = &[
+    0x36, 0x26, 0xB2, 0x75, 0x8E, 0x66, 0xCF, 0x11, 0xA6, 0xD9, 0x00, 0xAA, 0x00, 0x62, 0xCE, 0x6C,
+];
+const ASF_FILE_PROPERTIES_GUID: &[u8] = &[
+    0xA1, 0xDC, 0xAB, 0x8C, 0x47, 0xA9, 0xCF, 0x11, 0x8E, 0xE4, 0x00, 0xC0, 0x0C, 0x20, 0x53, 0x65,
+];
+const ASF_STREAM_PROPERTIES_GUID: &[u8] = &[
+    0x91, 0x07, 0xDC, 0xB7, 0xB7, 0xA9, 0xCF, 0x11, 0x8E, 0xE6, 0x00, 0xC0, 0x0C, 0x20, 0x53, 0x65,
+];
+const ASF_VIDEO_MEDIA_GUID: &[u8] = &[
+    0xC0, 0xEF, 0x19, 0xBC, 0x4D, 0x5B, 0xCF, 0x11, 0xA8, 0xFD, 0x00, 0x80, 0x5F, 0x5C, 0x44, 0x2B,
+];
+const ASF_AUDIO_MEDIA_GUID: &[u8] = &[
+    0x40, 0x9E, 0x69, 0xF8, 0x4D, 0x5B, 0xCF, 0x11, 0xA8, 0xFD, 0x00, 0x80, 0x5F, 0x5C, 0x44, 0x2B,
+];
+/// Size of an object's GUID and length
+const ASF_OBJECT_HEADER_SIZE: usize = 0x18;
+/// Size of the header object's own fields, after which its children start
+const ASF_HEADER_OBJECT_SIZE: usize = 0x1E;
+
+/// Carves ASF files (WMV and WMA) using the file size in their file
+/// properties object. Files larger than the maximum size are skipped.
+pub struct AsfCarver {
+    max_size: u64,
+}
+
+impl AsfCarver {
+    pub fn new(max_size: u64) -> AsfCarver {
+        AsfCarver { max_size }
+    }
+}
+
+impl Carver for AsfCarver {
+    fn name(&self) -> &'static str {
+        "asf"
+    }
+
+    fn extension(&self) -> &'static str {
+        "asf"
+    }
+
+    fn signatures(&self) -> &[&'static [u8]] {
+        &[ASF_HEADER_GUID]
+    }
+
+    fn validate(&self, header: &[u8]) -> bool {
+        header.len() >= ASF_HEADER_OBJECT_SIZE
+            && LittleEndian::read_u64(&header[0x10..]) >= ASF_HEADER_OBJECT_SIZE as u64
+    }
+
+    fn carve(&self, data: &[u8], offset: usize) -> Option<CarvedFile> {
+        let limit = carve_limit(data, offset, self.max_size);
+        let header_size = LittleEndian::read_u64(&data[offset + 0x10..]);
+        let header_end = match (offset as u64).checked_add(header_size) {
+            Some(header_end) => cmp::min(limit as u64, header_end) as usize,
+            None => {
+                debug!("ASF file at 0x{:X} has an invalid header size", offset);
+                return None;
+            }
+        };
+
+        let mut file_size = None;
+        let mut has_video = false;
+        let mut has_audio = false;
+
+        let mut pos = offset + ASF_HEADER_OBJECT_SIZE;
+        while pos + ASF_OBJECT_HEADER_SIZE <= header_end {
+            let guid = &data[pos..pos + 0x10];
+            let object_size = LittleEndian::read_u64(&data[pos + 0x10..]);
+            if object_size < ASF_OBJECT_HEADER_SIZE as u64
+                || object_size > (header_end - pos) as u64
+            {
+                break;
+            }
+
+            let body = &data[pos..pos + object_size as usize];
+            if guid == ASF_FILE_PROPERTIES_GUID && body.len() >= 0x30 {
+                file_size = Some(LittleEndian::read_u64(&body[0x28..]));
+            } else if guid == ASF_STREAM_PROPERTIES_GUID && body.len() >= 0x28 {
+                let stream_type = &body[0x18..0x28];
+                has_video |= stream_type == ASF_VIDEO_MEDIA_GUID;
+                has_audio |= stream_type == ASF_AUDIO_MEDIA_GUID;
+            }
+
+            pos += object_size as usize;
+        }
+
+        // Broadcast files leave the file size as zero, in which case the data
+        // object that follows the header is the best guess
+        let size = match file_size.filter(|size| *size > header_size) {
+            Some(size) => size,
+            None => {
+                let data_object = &data[header_end..];
+                if !data_object.starts_with(ASF_DATA_GUID) || data_object.len() < 0x18 {
+                    debug!("ASF file at 0x{:X} has no length", offset);
+                    return None;
+                }
+
+                match header_size.checked_add(LittleEndian::read_u64(&data_object[0x10..])) {
+                    Some(size) => size,
+                    None => {
+                        debug!("ASF file at 0x{:X} has an invalid data size", offset);
+                        return None;
+                    }
+                }
+            }
+        };
+
+        if size > (limit - offset) as u64 {
+            debug!(
+                "ASF file at 0x{:X} is larger than the maximum size (size: 0x{:X})",
+                offset, size
+            );
+            return None;
+        }
+
+        let extension = if has_video {
+            "wmv"
+        } else if has_audio {
+            "wma"
+        } else {
+            self.extension()
+        };
+
+        debug!(
+            "Got ASF file at offset 0x{:X} ({}, size: 0x{:X})",
+            offset, extension, size
+        );
+
+        Some(CarvedFile::new(self, offset as u64, Some(size)).with_extension(extension))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Header of a DXT1 texture, which is a volume texture if `depth` is given
+    fn dds_header(width: u32, height: u32, depth: Option<u32>) -> Vec<u8> {
+        let mut header = vec![0u8; DDS_HEADER_SIZE as usize];
+        header[..4].copy_from_slice(DDS_MAGIC);
+        LittleEndian::write_u32(&mut header[0x4..], 0x7C);
+        LittleEndian::write_u32(&mut header[0xC..], height);
+        LittleEndian::write_u32(&mut header[0x10..], width);
+        LittleEndian::write_u32(&mut header[0x4C..], 0x20);
+        LittleEndian::write_u32(&mut header[0x50..], DDSPF_FOURCC);
+        header[0x54..0x58].copy_from_slice(b"DXT1");
+        if let Some(depth) = depth {
+            LittleEndian::write_u32(&mut header[0x18..], depth);
+            LittleEndian::write_u32(&mut header[0x70..], DDSCAPS2_VOLUME);
+        }
+
+        header
+    }
+
+    #[test]
+    fn dds_size_covers_surfaces() {
+        // 8 bytes for each 4x4 block
+        assert_eq!(dds_file_size(&dds_header(64, 32, None)), Some(0x80 + 0x400));
+        assert_eq!(
+            dds_file_size(&dds_header(64, 32, Some(4))),
+            Some(0x80 + 0x1000)
+        );
+    }
+
+    #[test]
+    fn dds_with_huge_depth_is_capped() {
+        let header = dds_header(DDS_MAX_DIMENSION, DDS_MAX_DIMENSION, Some(u32::MAX));
+        assert_eq!(dds_file_size(&header), None);
+
+        let mut data = header;
+        data.resize(0x200, 0xAA);
+        let file = DdsCarver::new(0x100).carve(&data, 0).unwrap();
+        assert_eq!(file.size(), Some(0x100));
+    }
+
+    /// An ASF file whose header only holds a file properties object giving
+    /// the file's size
+    fn asf_file(header_size: u64, file_size: u64) -> Vec<u8> {
+        let mut data = vec![0u8; ASF_HEADER_OBJECT_SIZE];
+        data[..0x10].copy_from_slice(ASF_HEADER_GUID);
+        LittleEndian::write_u64(&mut data[0x10..], header_size);
+
+        let mut file_properties = vec![0u8; 0x68];
+        file_properties[..0x10].copy_from_slice(ASF_FILE_PROPERTIES_GUID);
+        LittleEndian::write_u64(&mut file_properties[0x10..], 0x68);
+        LittleEndian::write_u64(&mut file_properties[0x28..], file_size);
+        data.extend(&file_properties);
+
+        data.resize(0x200, 0xAA);
+        data
+    }
+
+    #[test]
+    fn asf_size_comes_from_file_properties() {
+        let data = asf_file(0x86, 0x180);
+
+        let file = AsfCarver::new(0x1000).carve(&data, 0).unwrap();
+        assert_eq!(file.size(), Some(0x180));
+    }
+
+    #[test]
+    fn asf_with_huge_sizes_is_skipped() {
+        let carver = AsfCarver::new(0x1000);
+
+        let mut data = vec![0u8; 0x10];
+        data.extend(asf_file(u64::MAX, 0x180));
+        assert!(carver.carve(&data, 0x10).is_none());
+        assert!(carver.carve(&asf_file(0x86, u64::MAX), 0x0).is_none());
+        // Larger than the data
+        assert!(carver.carve(&asf_file(0x86, 0x800), 0x0).is_none());
+    }
+}
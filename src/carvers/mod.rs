@@ -0,0 +1,376 @@
+//! Carvers for the file formats that the scanner knows about.
+
+use crate::bink::{self, BinkHeader};
+use crate::scanners::{CarvedFile, Carver, CarverRegistry};
+use crate::stfs::{self, XContentHeader};
+use crate::xdbf::{self, XdbfFile};
+use crate::xdvdfs::{self, XdvdfsVolume};
+use crate::xex::XexHeader;
+use std::cmp;
+use std::io::Cursor;
+
+mod archive;
+mod media;
+
+pub use self::archive::{XuizCarver, ZipCarver};
+pub use self::media::{AsfCarver, DdsCarver, JpegCarver, PngCarver, RiffCarver};
+
+/// Default limit on the size of files whose length can't be worked out
+pub const DEFAULT_MAX_CARVE_SIZE: u64 = 0x400_0000;
+
+/// Returns a registry containing every built-in carver. Files whose length
+/// can't be found are carved up to `max_carve_size` bytes.
+pub fn default_registry(max_carve_size: u64) -> CarverRegistry {
+    let mut registry = CarverRegistry::new();
+    registry.register(Box::new(XexCarver));
+    registry.register(Box::new(StfsCarver));
+    registry.register(Box::new(BinkCarver));
+    registry.register(Box::new(XdvdfsCarver));
+    registry.register(Box::new(XdbfCarver));
+    registry.register(Box::new(PngCarver::new(max_carve_size)));
+    registry.register(Box::new(JpegCarver::new(max_carve_size)));
+    registry.register(Box::new(DdsCarver::new(max_carve_size)));
+    registry.register(Box::new(RiffCarver));
+    registry.register(Box::new(AsfCarver::new(max_carve_size)));
+    registry.register(Box::new(ZipCarver::new(max_carve_size)));
+    registry.register(Box::new(XuizCarver::new(max_carve_size)));
+
+    registry
+}
+
+/// End of the area that a carver starting at `offset` may look at
+fn carve_limit(data: &[u8], offset: usize, max_carve_size: u64) -> usize {
+    cmp::min(data.len() as u64, offset as u64 + max_carve_size) as usize
+}
+
+/// Text that happens to contain one of the magics is usually followed by a
+/// space or a period
+fn looks_like_text(header: &[u8]) -> bool {
+    matches!(header.get(0x5), Some(0x20) | Some(0x2e))
+}
+
+/// Replaces characters that can't be used in file names on common
+/// filesystems
+pub fn sanitize_file_name(name: &str) -> String {
+    name.trim()
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+pub struct XexCarver;
+
+impl Carver for XexCarver {
+    fn name(&self) -> &'static str {
+        "xex"
+    }
+
+    fn extension(&self) -> &'static str {
+        "xex"
+    }
+
+    fn signatures(&self) -> &[&'static [u8]] {
+        &[b"XEX2"]
+    }
+
+    fn validate(&self, header: &[u8]) -> bool {
+        !looks_like_text(header)
+    }
+
+    fn carve(&self, data: &[u8], offset: usize) -> Option<CarvedFile> {
+        let xex_data = &data[offset..];
+        let header = match XexHeader::parse(xex_data) {
+            Ok(header) => header,
+            Err(e) => {
+                debug!("Invalid XEX2 header at 0x{:X}: {}", offset, e);
+                return None;
+            }
+        };
+
+        let size = header
+            .file_size(xex_data)
+            .filter(|size| *size <= xex_data.len() as u64);
+
+        debug!(
+            "Got XEX2 at offset 0x{:X} (title ID: {}, original name: {}, size: {})",
+            offset,
+            header
+                .title_id()
+                .map(|id| format!("{:08X}", id))
+                .unwrap_or_else(|| "-".to_owned()),
+            header.original_pe_name().unwrap_or("-"),
+            size.map(|size| format!("0x{:X}", size))
+                .unwrap_or_else(|| "unknown".to_owned()),
+        );
+
+        // The suggested name includes the original PE name, which comes from
+        // the file itself
+        let file = CarvedFile::new(self, offset as u64, size);
+        match header
+            .suggested_name()
+            .map(|name| sanitize_file_name(&name))
+        {
+            Some(name) if !name.is_empty() => Some(file.with_name(name)),
+            _ => Some(file),
+        }
+    }
+}
+
+pub struct StfsCarver;
+
+impl Carver for StfsCarver {
+    fn name(&self) -> &'static str {
+        "stfs"
+    }
+
+    fn signatures(&self) -> &[&'static [u8]] {
+        &[b"CON ", b"LIVE", b"PIRS"]
+    }
+
+    fn validate(&self, header: &[u8]) -> bool {
+        !looks_like_text(header)
+    }
+
+    fn carve(&self, data: &[u8], offset: usize) -> Option<CarvedFile> {
+        let header = match XContentHeader::parse(&mut Cursor::new(&data[offset..])) {
+            Ok(header) => header,
+            Err(e) => {
+                debug!("Invalid STFS header at 0x{:X}: {}", offset, e);
+                return None;
+            }
+        };
+
+        let size = stfs::package_size(&header);
+
+        debug!(
+            "Got STFS package at offset 0x{:X} with name: {} (title ID: {:08X}, size: 0x{:X})",
+            offset,
+            header.display_name(),
+            header.title_id,
+            size
+        );
+
+        // Packages are often rewritten in place, so the offset keeps copies of
+        // the same package apart
+        let display_name = sanitize_file_name(header.display_name());
+        let name = if display_name.is_empty() {
+            format!("{:08X}_{:X}", header.title_id, offset)
+        } else {
+            format!("{:08X}_{}_{:X}", header.title_id, display_name, offset)
+        };
+
+        Some(CarvedFile::new(self, offset as u64, Some(size)).with_name(name))
+    }
+}
+
+pub struct BinkCarver;
+
+impl Carver for BinkCarver {
+    fn name(&self) -> &'static str {
+        "bink"
+    }
+
+    fn extension(&self) -> &'static str {
+        "bik"
+    }
+
+    fn signatures(&self) -> &[&'static [u8]] {
+        &bink::SIGNATURES
+    }
+
+    fn validate(&self, header: &[u8]) -> bool {
+        header.len() >= bink::HEADER_SIZE && !looks_like_text(header)
+    }
+
+    fn carve(&self, data: &[u8], offset: usize) -> Option<CarvedFile> {
+        let header = match BinkHeader::parse(&data[offset..]) {
+            Ok(header) => header,
+            Err(e) => {
+                debug!("Invalid Bink header at 0x{:X}: {}", offset, e);
+                return None;
+            }
+        };
+
+        let duration = header.duration().round() as u64;
+        let (fps_dividend, fps_divider) = header.fps();
+        debug!(
+            "Got {} video at offset 0x{:X} ({}x{}, {} frames at {:.2} fps, {} audio tracks, size: 0x{:X})",
+            header.version(),
+            offset,
+            header.width(),
+            header.height(),
+            header.frame_count(),
+            fps_dividend as f64 / fps_divider as f64,
+            header.audio_track_count(),
+            header.file_size(),
+        );
+
+        let name = format!(
+            "{}x{}_{}m{:02}s_{:X}",
+            header.width(),
+            header.height(),
+            duration / 60,
+            duration % 60,
+            offset
+        );
+
+        Some(CarvedFile::new(self, offset as u64, Some(header.file_size())).with_name(name))
+    }
+}
+
+/// Finds game partitions by the XDVDFS volume descriptor in their 32nd sector
+pub struct XdvdfsCarver;
+
+impl Carver for XdvdfsCarver {
+    fn name(&self) -> &'static str {
+        "xdvdfs"
+    }
+
+    fn extension(&self) -> &'static str {
+        "iso"
+    }
+
+    fn signatures(&self) -> &[&'static [u8]] {
+        &[xdvdfs::MAGIC]
+    }
+
+    fn alignment(&self) -> usize {
+        xdvdfs::SECTOR_SIZE as usize
+    }
+
+    fn carve(&self, data: &[u8], offset: usize) -> Option<CarvedFile> {
+        let start = offset.checked_sub(xdvdfs::VOLUME_DESCRIPTOR_OFFSET as usize)?;
+
+        let size = XdvdfsVolume::open_at(Cursor::new(data), start as u64)
+            .and_then(|mut volume| volume.partition_size());
+        let size = match size {
+            Ok(size) => size,
+            Err(e) => {
+                debug!("Invalid XDVDFS volume at 0x{:X}: {}", start, e);
+                return None;
+            }
+        };
+
+        debug!(
+            "Got XDVDFS volume at offset 0x{:X} (size: 0x{:X})",
+            start, size
+        );
+
+        Some(CarvedFile::new(self, start as u64, Some(size)))
+    }
+}
+
+/// Finds XDBF databases, which are mostly profile and title GPDs
+pub struct XdbfCarver;
+
+impl Carver for XdbfCarver {
+    fn name(&self) -> &'static str {
+        "xdbf"
+    }
+
+    fn extension(&self) -> &'static str {
+        "gpd"
+    }
+
+    fn signatures(&self) -> &[&'static [u8]] {
+        &[xdbf::MAGIC]
+    }
+
+    fn validate(&self, header: &[u8]) -> bool {
+        header.get(4..8) == Some(&[0x00, 0x01, 0x00, 0x00])
+    }
+
+    fn carve(&self, data: &[u8], offset: usize) -> Option<CarvedFile> {
+        let file = match XdbfFile::parse(&data[offset..]) {
+            Ok(file) => file,
+            Err(e) => {
+                debug!("Invalid XDBF file at 0x{:X}: {}", offset, e);
+                return None;
+            }
+        };
+
+        debug!(
+            "Got XDBF file at offset 0x{:X} ({} entries, size: 0x{:X})",
+            offset,
+            file.entries().len(),
+            file.file_size()
+        );
+
+        Some(CarvedFile::new(self, offset as u64, Some(file.file_size())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::{BigEndian, ByteOrder};
+
+    fn write_u32s(data: &mut [u8], offset: usize, values: &[u32]) {
+        for (i, value) in values.iter().enumerate() {
+            BigEndian::write_u32(&mut data[offset + i * 4..], *value);
+        }
+    }
+
+    /// A basic compressed XEX2 file whose basefile is stored as two blocks of
+    /// data, 0xC00 bytes in total
+    fn xex_file(pe_name: &str) -> Vec<u8> {
+        let mut data = vec![0u8; 0x1C00];
+        data[..4].copy_from_slice(b"XEX2");
+        // Module flags, PE data offset, reserved, security info offset and
+        // optional header count
+        write_u32s(&mut data, 0x4, &[0x1, 0x1000, 0, 0x100, 3]);
+        // Execution ID, original PE name and file format info
+        write_u32s(
+            &mut data,
+            0x18,
+            &[0x0004_0006, 0x200, 0x0001_83FF, 0x240, 0x0000_03FF, 0x280],
+        );
+        // Security info size and image size
+        write_u32s(&mut data, 0x100, &[0x180, 0x3000]);
+        // Media ID, version, base version and title ID
+        write_u32s(
+            &mut data,
+            0x200,
+            &[0x1111_1111, 0x2000, 0x1000, 0x4D53_07E6],
+        );
+
+        write_u32s(&mut data, 0x240, &[4 + pe_name.len() as u32 + 1]);
+        data[0x244..0x244 + pe_name.len()].copy_from_slice(pe_name.as_bytes());
+
+        // Info size, no encryption, basic compression and the blocks
+        write_u32s(&mut data, 0x280, &[0x18, 1, 0x800, 0x800, 0x400, 0x400]);
+
+        data[0x1000..0x1002].copy_from_slice(b"MZ");
+        data
+    }
+
+    #[test]
+    fn xex_size_includes_basefile() {
+        let mut data = xex_file("default.exe");
+        data.extend(vec![0xAA; 0x100]);
+
+        let file = XexCarver.carve(&data, 0).unwrap();
+        assert_eq!(file.size(), Some(0x1C00));
+        assert_eq!(file.file_name(), "4D5307E6_default.xex");
+    }
+
+    #[test]
+    fn truncated_xex_has_no_size() {
+        let data = xex_file("default.exe");
+
+        let file = XexCarver.carve(&data[..0x1800], 0).unwrap();
+        assert_eq!(file.size(), None);
+    }
+
+    #[test]
+    fn xex_name_is_sanitized() {
+        let data = xex_file("../../evil.exe");
+
+        let file = XexCarver.carve(&data, 0).unwrap();
+        assert_eq!(file.file_name(), "4D5307E6_.._.._evil.xex");
+    }
+}
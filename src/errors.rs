@@ -1,6 +1,10 @@
+// err-derive generates its impls inside of an anonymous const
+#![allow(non_local_definitions)]
+
 use err_derive::Error;
 use std::io;
 
+/// Errors that can occur while parsing a disk
 #[derive(Debug, Error)]
 pub enum DiskError {
     #[error(
@@ -14,10 +18,28 @@ pub enum DiskError {
     IoError(io::Error),
 
     #[error(
-        display = "filesystem has invalid magic. expected 0x58544146, got 0x{:X}",
-        0
+        display = "filesystem has invalid magic. expected XTAF or FATX, got 0x{:X}",
+        magic
     )]
     InvalidFilesystemMagic { magic: u32, offset: u64 },
+
+    #[error(
+        display = "filesystem has invalid sectors per cluster 0x{:X}",
+        sectors_per_cluster
+    )]
+    InvalidSectorsPerCluster {
+        sectors_per_cluster: u32,
+        offset: u64,
+    },
+
+    #[error(display = "no valid partitions were found on the disk")]
+    NoPartitionsFound,
+
+    #[error(display = "no such file or directory: {}", 0)]
+    PathNotFound(String),
+
+    #[error(display = "not a directory: {}", 0)]
+    NotADirectory(String),
 }
 
 impl From<io::Error> for DiskError {
@@ -25,3 +47,37 @@ impl From<io::Error> for DiskError {
         DiskError::IoError(error)
     }
 }
+
+/// Errors that can occur while parsing a file format found on the disk
+#[derive(Debug, Error)]
+pub enum FormatError {
+    #[error(display = "error occurred while reading data: {}", 0)]
+    IoError(io::Error),
+
+    #[error(display = "{} has invalid magic 0x{:X}", format, magic)]
+    InvalidMagic { format: &'static str, magic: u32 },
+
+    #[error(display = "{} has an invalid header: {}", format, reason)]
+    InvalidHeader {
+        format: &'static str,
+        reason: &'static str,
+    },
+
+    #[error(display = "unsupported {}: {}", format, reason)]
+    Unsupported {
+        format: &'static str,
+        reason: &'static str,
+    },
+
+    #[error(display = "no such file or directory: {}", 0)]
+    PathNotFound(String),
+
+    #[error(display = "not a directory: {}", 0)]
+    NotADirectory(String),
+}
+
+impl From<io::Error> for FormatError {
+    fn from(error: io::Error) -> Self {
+        FormatError::IoError(error)
+    }
+}
@@ -1,17 +1,30 @@
+//! Parsing of FATX/XTAF filesystems and the partition layouts of Xbox and
+//! Xbox 360 hard drives.
+
 use crate::errors::DiskError;
-use byteorder::{BigEndian, ByteOrder, ReadBytesExt};
+use byteorder::{BigEndian, LittleEndian, ReadBytesExt};
+use chrono::{Duration, NaiveDate, NaiveDateTime};
+use filetime::FileTime;
+use std::borrow::Cow;
+use std::cmp;
+use std::collections::HashSet;
 use std::fmt;
 use std::fs::{self, File};
 use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
-use std::ops::Deref;
+use std::iter::FromIterator;
+use std::ops::{Deref, Range};
 use std::path::Path;
 
-const ENTRY_SIZE: usize = 0x40;
+/// Size of a directory entry
+pub const ENTRY_SIZE: usize = 0x40;
 const SECTOR_SIZE: usize = 0x200;
 pub const DELETED_FILE_FLAG: u8 = 0xE5;
 const MAX_FILENAME_LEN: usize = 0x2A;
 const FAT_TABLE_OFFSET: usize = 0x1000;
+const MAX_SECTORS_PER_CLUSTER: u32 = 0x80;
 
+/// Header found at the start of Xbox 360 devkit hard drives, which describes
+/// the "Data" and "System" partitions
 #[derive(Debug)]
 pub struct DevkitHddInfo<'a> {
     major: u16,
@@ -22,9 +35,57 @@ pub struct DevkitHddInfo<'a> {
 }
 
 impl<'a> DevkitHddInfo<'a> {
-    pub fn partitions(&self) -> &[Partition] {
+    pub fn partitions(&self) -> &[Partition<'_>] {
         &self.partitions
     }
+
+    /// Returns the header version as (major, minor, revision, patch)
+    pub fn version(&self) -> (u16, u16, u16, u16) {
+        (self.major, self.minor, self.revision, self.patch)
+    }
+}
+
+/// Fixed partition layout used by retail Xbox 360 hard drives. The final
+/// partition has no fixed length and extends to the end of the disk.
+const RETAIL_PARTITIONS: [(u64, Option<usize>, &str); 6] = [
+    (0x8_0000, Some(0x8000_0000), "SystemCache"),
+    (0x8008_0000, Some(0xA0E3_0000), "GameCache"),
+    (0x1_0C08_0000, Some(0xCE3_0000), "SysExt"),
+    (0x1_18EB_0000, Some(0x800_0000), "SysExt2"),
+    (0x1_20EB_0000, Some(0x1000_0000), "Compatibility"),
+    (0x1_30EB_0000, None, "Content"),
+];
+
+/// Partitions of a retail Xbox 360 hard drive. Partitions that are missing or
+/// unformatted are omitted.
+#[derive(Debug)]
+pub struct RetailHddInfo<'a> {
+    partitions: Vec<Partition<'a>>,
+}
+
+impl<'a> RetailHddInfo<'a> {
+    pub fn partitions(&self) -> &[Partition<'_>] {
+        &self.partitions
+    }
+}
+
+/// Partition layout of a disk as detected by `hdd_partitions`
+#[derive(Debug)]
+pub enum HddInfo<'a> {
+    Devkit(DevkitHddInfo<'a>),
+    Retail(RetailHddInfo<'a>),
+    /// Partitions found by sweeping the disk for superblocks
+    Discovered(Vec<Partition<'a>>),
+}
+
+impl<'a> HddInfo<'a> {
+    pub fn partitions(&self) -> &[Partition<'_>] {
+        match self {
+            HddInfo::Devkit(info) => info.partitions(),
+            HddInfo::Retail(info) => info.partitions(),
+            HddInfo::Discovered(partitions) => partitions,
+        }
+    }
 }
 
 bitflags! {
@@ -40,7 +101,39 @@ bitflags! {
     }
 }
 
-#[derive(Debug)]
+/// Byte order of the filesystem. Xbox 360 partitions (XTAF) are big-endian
+/// while original Xbox partitions (FATX) are little-endian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endian {
+    Big,
+    Little,
+}
+
+impl Endian {
+    pub fn from_magic(magic: &[u8]) -> Option<Endian> {
+        match magic {
+            b"XTAF" => Some(Endian::Big),
+            b"FATX" => Some(Endian::Little),
+            _ => None,
+        }
+    }
+
+    pub fn read_u16<R: Read>(self, reader: &mut R) -> Result<u16, io::Error> {
+        match self {
+            Endian::Big => reader.read_u16::<BigEndian>(),
+            Endian::Little => reader.read_u16::<LittleEndian>(),
+        }
+    }
+
+    pub fn read_u32<R: Read>(self, reader: &mut R) -> Result<u32, io::Error> {
+        match self {
+            Endian::Big => reader.read_u32::<BigEndian>(),
+            Endian::Little => reader.read_u32::<LittleEndian>(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
 enum EntrySize {
     Fat16,
     Fat32,
@@ -61,24 +154,83 @@ impl fmt::Debug for DataRef<'_> {
     }
 }
 
+/// Typed view of a partition's FAT that reads entries straight out of the
+/// disk image
+#[derive(Debug, Clone, Copy)]
+pub struct FatTable<'a> {
+    table: &'a [u8],
+    entry_size: EntrySize,
+    endian: Endian,
+}
+
+impl<'a> FatTable<'a> {
+    /// Number of entries in the table, which may be cut short in a truncated
+    /// dump
+    pub fn len(&self) -> usize {
+        self.table.len() / self.entry_size()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn entry_size(&self) -> usize {
+        match self.entry_size {
+            EntrySize::Fat16 => 2,
+            EntrySize::Fat32 => 4,
+        }
+    }
+
+    /// The entry for `cluster`, which is the next cluster in its chain, zero
+    /// if it's free, or an end of chain marker
+    pub fn get(&self, cluster: usize) -> Option<u32> {
+        let start = cluster.checked_mul(self.entry_size())?;
+        let bytes = self.table.get(start..start + self.entry_size())?;
+
+        Some(match (self.entry_size, self.endian) {
+            (EntrySize::Fat16, Endian::Big) => u16::from_be_bytes([bytes[0], bytes[1]]) as u32,
+            (EntrySize::Fat16, Endian::Little) => u16::from_le_bytes([bytes[0], bytes[1]]) as u32,
+            (EntrySize::Fat32, Endian::Big) => {
+                u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+            }
+            (EntrySize::Fat32, Endian::Little) => {
+                u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+            }
+        })
+    }
+
+    /// Whether `next` ends a chain instead of pointing at another cluster
+    pub fn is_end_of_chain(&self, next: u32) -> bool {
+        match self.entry_size {
+            EntrySize::Fat16 => matches!(next, 0xFFFF | 0xFFF8 | 0x0),
+            EntrySize::Fat32 => matches!(next, 0xFFFF_FFFF | 0xFFFF_FFF8 | 0x0),
+        }
+    }
+}
+
+/// A single FATX/XTAF partition. All offsets returned by a partition are
+/// relative to the start of the disk, not the partition.
 #[derive(Debug)]
 pub struct Partition<'a> {
     offset: u64,
     len: usize,
-    name: &'static str,
+    name: String,
     data: DataRef<'a>,
     sectors_per_cluster: usize,
     root_dir_cluster: usize,
     entry_size: EntrySize,
     data_offset: u64,
+    endian: Endian,
 }
 
 impl<'a> Partition<'a> {
+    /// Parses the superblock of the partition at `offset` inside of `data`,
+    /// which should be the whole disk
     pub fn new(
         data: &'a [u8],
         offset: u64,
         len: usize,
-        name: &'static str,
+        name: &str,
     ) -> Result<Partition<'a>, DiskError> {
         debug!(
             "Reading partition at offset 0x{:X} with length: 0x{:X}",
@@ -94,17 +246,31 @@ impl<'a> Partition<'a> {
 
         let mut cursor = Cursor::new(data);
         cursor.seek(SeekFrom::Start(offset))?;
-        let magic = cursor.read_u32::<BigEndian>()?;
-        if magic != u32::from_be_bytes(*b"XTAF") {
-            let offset = cursor.position();
-            return Err(DiskError::InvalidFilesystemMagic { magic, offset });
-        }
+        let mut magic = [0u8; 4];
+        cursor.read_exact(&mut magic)?;
+        let endian = match Endian::from_magic(&magic) {
+            Some(endian) => endian,
+            None => {
+                let magic = u32::from_be_bytes(magic);
+                let offset = cursor.position();
+                return Err(DiskError::InvalidFilesystemMagic { magic, offset });
+            }
+        };
+        debug!("Filesystem endianness: {:?}", endian);
 
         cursor.seek(SeekFrom::Start(offset + 0x8))?;
-        let sectors_per_cluster = cursor.read_u32::<BigEndian>()?;
+        let sectors_per_cluster = endian.read_u32(&mut cursor)?;
         debug!("sectors per cluster: 0x{:X}", sectors_per_cluster);
 
-        let root_dir_cluster = cursor.read_u32::<BigEndian>()?;
+        // The cluster size has to be a power of two for the shift below
+        if !sectors_per_cluster.is_power_of_two() || sectors_per_cluster > MAX_SECTORS_PER_CLUSTER {
+            return Err(DiskError::InvalidSectorsPerCluster {
+                sectors_per_cluster,
+                offset,
+            });
+        }
+
+        let root_dir_cluster = endian.read_u32(&mut cursor)?;
 
         debug!("Cluster size: 0x{:X}", sectors_per_cluster << 9);
         debug!(
@@ -126,7 +292,7 @@ impl<'a> Partition<'a> {
         Ok(Partition {
             offset,
             len,
-            name,
+            name: name.to_owned(),
             data: DataRef(data),
             sectors_per_cluster: sectors_per_cluster as usize,
             root_dir_cluster: root_dir_cluster as usize,
@@ -136,6 +302,7 @@ impl<'a> Partition<'a> {
                 EntrySize::Fat32
             },
             data_offset: offset + 0x1000 + allocation_table_size as u64,
+            endian,
         })
     }
 
@@ -147,6 +314,10 @@ impl<'a> Partition<'a> {
         self.len
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
     pub fn sectors_per_cluster(&self) -> usize {
         self.sectors_per_cluster
     }
@@ -159,15 +330,143 @@ impl<'a> Partition<'a> {
         self.root_dir_cluster
     }
 
+    pub fn fat_offset(&self) -> u64 {
+        self.offset + FAT_TABLE_OFFSET as u64
+    }
+
+    /// Size in bytes of a single FAT entry
+    pub fn fat_entry_size(&self) -> usize {
+        match self.entry_size {
+            EntrySize::Fat16 => 2,
+            EntrySize::Fat32 => 4,
+        }
+    }
+
+    /// Number of clusters in the data area of the partition
+    pub fn cluster_count(&self) -> usize {
+        let data_len = (self.offset + self.len as u64).saturating_sub(self.data_offset);
+        data_len as usize / self.cluster_size()
+    }
+
+    pub fn endian(&self) -> Endian {
+        self.endian
+    }
+
+    /// Year that entry timestamps on this partition are relative to
+    pub fn timestamp_epoch(&self) -> i32 {
+        match self.endian {
+            Endian::Big => 1980,
+            Endian::Little => 2000,
+        }
+    }
+
     pub fn root_dir(&self) -> Result<Directory, io::Error> {
         debug!(
             "Getting root directory for partition at 0x{:X}",
             self.offset
         );
 
-        let root_entry = Entry::new_root(&self)?;
+        let root_entry = Entry::new_root(self)?;
+
+        Directory::parse(&root_entry, self, "/".to_owned())
+    }
+
+    /// Finds the entry at `path`, where `path` is relative to the root of the
+    /// partition (e.g. `Content/0000000000000000/FFFE07D1`). Components are
+    /// separated by `/` and matched case-insensitively. Deleted entries are
+    /// never matched.
+    pub fn lookup(&self, path: &str) -> Result<Entry, DiskError> {
+        let mut entry = Entry::new_root(self)?;
+
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            if !entry.is_dir() {
+                return Err(DiskError::NotADirectory(entry.name().to_owned()));
+            }
+
+            let dir = Directory::parse(&entry, self, entry.name().to_owned())?;
+            entry = dir
+                .entries()
+                .iter()
+                .find(|e| !e.is_deleted() && e.name().eq_ignore_ascii_case(component))
+                .cloned()
+                .ok_or_else(|| DiskError::PathNotFound(path.to_owned()))?;
+        }
+
+        Ok(entry)
+    }
+
+    /// Reads the directory at `path`. See `lookup` for how paths are matched.
+    pub fn read_dir(&self, path: &str) -> Result<Directory, DiskError> {
+        let entry = self.lookup(path)?;
+        if !entry.is_dir() {
+            return Err(DiskError::NotADirectory(path.to_owned()));
+        }
+
+        let name = if entry.name().is_empty() {
+            "/".to_owned()
+        } else {
+            entry.name().to_owned()
+        };
+
+        Ok(Directory::parse(&entry, self, name)?)
+    }
+
+    /// The root directory and every entry reachable from it that isn't
+    /// deleted, along with their paths (e.g. `/Content/0000000000000000`)
+    pub fn live_entries(&self) -> Result<Vec<(String, Entry)>, io::Error> {
+        let root = Entry::new_root(self)?;
+        let mut entries = vec![("/".to_owned(), root.clone())];
+        let mut visited_dirs = HashSet::new();
+        let mut pending_dirs = vec![(String::new(), root)];
+
+        // Corrupted directories can point back at their parents, so each
+        // directory is only walked once
+        while let Some((dir_path, dir_entry)) = pending_dirs.pop() {
+            if !visited_dirs.insert(dir_entry.block()) {
+                continue;
+            }
+
+            let dir = Directory::parse(&dir_entry, self, dir_entry.name().to_owned())?;
+            for entry in dir.entries().iter().filter(|e| !e.is_deleted()) {
+                let path = format!("{}/{}", dir_path, entry.name());
+                if entry.is_dir() {
+                    pending_dirs.push((path.clone(), entry.clone()));
+                }
+
+                entries.push((path, entry.clone()));
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// Reads the FAT to find out which clusters are in use. Entries that are
+    /// missing from a truncated dump are treated as free.
+    pub fn allocation_bitmap(&self) -> AllocationBitmap {
+        let cluster_count = self.cluster_count();
+        let mut bitmap = AllocationBitmap {
+            bits: vec![0; (cluster_count + 1).div_ceil(64)],
+            cluster_count,
+        };
+
+        let fat = self.fat();
+        for cluster in 1..=cmp::min(cluster_count, fat.len().saturating_sub(1)) {
+            if fat.get(cluster) != Some(0) {
+                bitmap.bits[cluster / 64] |= 1 << (cluster % 64);
+            }
+        }
 
-        Directory::parse(&root_entry, &self, "/".to_owned())
+        bitmap
+    }
+
+    /// Cluster containing the disk offset `offset`, if it's in the data area
+    /// of the partition
+    pub fn cluster_at(&self, offset: u64) -> Option<usize> {
+        if offset < self.data_offset || offset >= self.offset + self.len as u64 {
+            return None;
+        }
+
+        Some(((offset - self.data_offset) / self.cluster_size() as u64) as usize + 1)
     }
 
     pub fn data_start(&self) -> u64 {
@@ -178,50 +477,46 @@ impl<'a> Partition<'a> {
         self.data.0
     }
 
-    pub fn block_chain_from_root(&self, root: usize) -> Result<Vec<usize>, io::Error> {
+    /// The partition's FAT, which runs up to the start of the data area
+    pub fn fat(&self) -> FatTable<'_> {
+        let start = cmp::min(self.fat_offset(), self.data().len() as u64) as usize;
+        let end = cmp::min(self.data_offset, self.data().len() as u64) as usize;
+
+        FatTable {
+            table: &self.data()[start..end],
+            entry_size: self.entry_size,
+            endian: self.endian,
+        }
+    }
+
+    /// Follows the FAT from `root` until the end of the chain. Chains that
+    /// loop back on themselves or point outside of the FAT are cut short.
+    pub fn block_chain_from_root(&self, root: usize) -> ClusterChain {
         debug!("Reading block chain from index 0x{:X}", root);
 
-        let mut cursor = Cursor::new(self.data());
-        let table_start = self.offset + 0x1000;
-
-        let mut chain = vec![];
-
-        let mut next = root;
-        loop {
-            match self.entry_size {
-                EntrySize::Fat16 => match next {
-                    0xffffusize | 0xfff8usize | 0x0 => break,
-                    _ => {}
-                },
-                EntrySize::Fat32 => match next {
-                    0xffffffffusize | 0xfffffff8usize | 0x0 => break,
-                    _ => {}
-                },
+        let fat = self.fat();
+        let mut chain = ClusterChain::default();
+
+        let mut visited = HashSet::new();
+        let mut next = root as u32;
+        while !fat.is_end_of_chain(next) {
+            if !visited.insert(next) {
+                debug!("Block chain from 0x{:X} loops back to 0x{:X}", root, next);
+                break;
             }
 
-            debug!("next = 0x{:X}", next);
-            chain.push(next);
-
-            cursor.seek(SeekFrom::Start(
-                table_start
-                    + (next as u64
-                        * match self.entry_size {
-                            EntrySize::Fat16 => 2,
-                            EntrySize::Fat32 => 4,
-                        }),
-            ))?;
-
-            match self.entry_size {
-                EntrySize::Fat16 => {
-                    next = cursor.read_u16::<BigEndian>()? as usize;
-                }
-                EntrySize::Fat32 => {
-                    next = cursor.read_u32::<BigEndian>()? as usize;
+            chain.push(next as usize);
+
+            next = match fat.get(next as usize) {
+                Some(next) => next,
+                None => {
+                    debug!("Cluster 0x{:X} is outside of the FAT", next);
+                    break;
                 }
-            }
+            };
         }
 
-        Ok(chain)
+        chain
     }
 
     pub fn block_offset(&self, block: usize) -> u64 {
@@ -238,44 +533,265 @@ impl<'a> Partition<'a> {
     }
 }
 
+/// Which clusters of a partition are in use according to the FAT. Clusters
+/// are numbered from 1, like in block chains.
+#[derive(Debug, Clone)]
+pub struct AllocationBitmap {
+    bits: Vec<u64>,
+    cluster_count: usize,
+}
+
+impl AllocationBitmap {
+    /// Whether `cluster` has a FAT entry. Clusters outside of the partition
+    /// are never allocated.
+    pub fn is_allocated(&self, cluster: usize) -> bool {
+        cluster != 0
+            && cluster <= self.cluster_count
+            && self.bits[cluster / 64] & (1 << (cluster % 64)) != 0
+    }
+
+    pub fn cluster_count(&self) -> usize {
+        self.cluster_count
+    }
+
+    pub fn allocated_count(&self) -> usize {
+        self.bits
+            .iter()
+            .map(|bits| bits.count_ones() as usize)
+            .sum()
+    }
+
+    pub fn free_count(&self) -> usize {
+        self.cluster_count - self.allocated_count()
+    }
+}
+
+/// A run of consecutive clusters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extent {
+    pub start: usize,
+    pub len: usize,
+}
+
+impl Extent {
+    pub fn clusters(&self) -> Range<usize> {
+        self.start..self.start + self.len
+    }
+}
+
+/// A cluster chain, stored as the runs of consecutive clusters that make it
+/// up since files are rarely fragmented
+#[derive(Debug, Clone, Default)]
+pub struct ClusterChain {
+    extents: Vec<Extent>,
+    /// Position in the chain of the first cluster of each extent
+    extent_indices: Vec<usize>,
+    len: usize,
+}
+
+impl ClusterChain {
+    /// Appends `cluster` to the end of the chain
+    pub fn push(&mut self, cluster: usize) {
+        match self.extents.last_mut() {
+            Some(last) if last.start + last.len == cluster => last.len += 1,
+            _ => {
+                self.extents.push(Extent {
+                    start: cluster,
+                    len: 1,
+                });
+                self.extent_indices.push(self.len);
+            }
+        }
+
+        self.len += 1;
+    }
+
+    pub fn extents(&self) -> &[Extent] {
+        &self.extents
+    }
+
+    /// Number of clusters in the chain
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn first(&self) -> Option<usize> {
+        self.extents.first().map(|extent| extent.start)
+    }
+
+    /// The cluster at position `index` in the chain
+    pub fn get(&self, index: usize) -> Option<usize> {
+        if index >= self.len {
+            return None;
+        }
+
+        let extent = self.extent_indices.partition_point(|start| *start <= index) - 1;
+        Some(self.extents[extent].start + index - self.extent_indices[extent])
+    }
+
+    /// Every cluster in the chain, in order
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.extents.iter().flat_map(Extent::clusters)
+    }
+}
+
+impl FromIterator<usize> for ClusterChain {
+    fn from_iter<I: IntoIterator<Item = usize>>(clusters: I) -> ClusterChain {
+        let mut chain = ClusterChain::default();
+        for cluster in clusters {
+            chain.push(cluster);
+        }
+
+        chain
+    }
+}
+
+/// Maps allocated clusters back to the live entries whose chains contain them
+#[derive(Debug, Clone)]
+pub struct ClusterIndex {
+    files: Vec<(String, Entry)>,
+    /// Sorted by first cluster
+    extents: Vec<ClusterExtent>,
+    /// The furthest end of any extent up to and including the same index in
+    /// `extents`. Extents overlap when chains are cross-linked, so this is
+    /// what bounds the search for an extent containing a cluster.
+    max_ends: Vec<usize>,
+}
+
+/// A run of consecutive clusters in an entry's chain
+#[derive(Debug, Clone)]
+struct ClusterExtent {
+    start: usize,
+    count: usize,
+    /// Index into `ClusterIndex::files`
+    file: usize,
+    /// Position of the first cluster in the entry's chain
+    chain_index: usize,
+}
+
+/// The live entry that a cluster belongs to
+#[derive(Debug, Clone, Copy)]
+pub struct ClusterOwner<'i> {
+    pub path: &'i str,
+    pub entry: &'i Entry,
+    /// Position of the cluster in the entry's block chain
+    pub chain_index: usize,
+}
+
+impl ClusterIndex {
+    /// Indexes every live entry on the partition
+    pub fn build(partition: &Partition) -> Result<ClusterIndex, io::Error> {
+        Ok(ClusterIndex::from_entries(partition.live_entries()?))
+    }
+
+    /// Indexes the given entries, which are paired with their paths
+    pub fn from_entries(files: Vec<(String, Entry)>) -> ClusterIndex {
+        let mut extents: Vec<ClusterExtent> = vec![];
+
+        for (file, (_, entry)) in files.iter().enumerate() {
+            let mut chain_index = 0;
+            for extent in entry.block_chain().extents() {
+                extents.push(ClusterExtent {
+                    start: extent.start,
+                    count: extent.len,
+                    file,
+                    chain_index,
+                });
+                chain_index += extent.len;
+            }
+        }
+
+        extents.sort_by_key(|extent| extent.start);
+
+        let max_ends = extents
+            .iter()
+            .scan(0, |max_end, extent| {
+                *max_end = cmp::max(*max_end, extent.start + extent.count);
+                Some(*max_end)
+            })
+            .collect();
+
+        ClusterIndex {
+            files,
+            extents,
+            max_ends,
+        }
+    }
+
+    /// The indexed entries and their paths
+    pub fn files(&self) -> &[(String, Entry)] {
+        &self.files
+    }
+
+    /// Finds the entry whose chain contains `cluster`. If the cluster is
+    /// cross-linked, only one of its owners is returned.
+    pub fn owner(&self, cluster: usize) -> Option<ClusterOwner<'_>> {
+        let end = self
+            .extents
+            .partition_point(|extent| extent.start <= cluster);
+
+        // Walk back over every extent starting at or before the cluster until
+        // none of the remaining ones can reach it
+        let index = (0..end)
+            .rev()
+            .take_while(|&index| self.max_ends[index] > cluster)
+            .find(|&index| cluster < self.extents[index].start + self.extents[index].count)?;
+        let extent = &self.extents[index];
+
+        let (path, entry) = &self.files[extent.file];
+        Some(ClusterOwner {
+            path,
+            entry,
+            chain_index: extent.chain_index + cluster - extent.start,
+        })
+    }
+}
+
+/// The entries contained in a directory's cluster chain
 pub struct Directory {
     name: String,
     entries: Vec<Entry>,
 }
 
 impl Directory {
+    /// Reads the directory pointed to by `entry`
     pub fn parse(
         entry: &Entry,
         partition: &Partition,
         name: String,
     ) -> Result<Directory, io::Error> {
-        if entry.block_chain().len() == 0 {
-            return Ok(Directory {
-                name: name,
-                entries: vec![],
-            });
-        }
+        // Deleted entries can have a first cluster of zero, which leaves them
+        // with an empty chain
+        let first_cluster = match entry.block_chain().first() {
+            Some(cluster) => cluster,
+            None => {
+                return Ok(Directory {
+                    name,
+                    entries: vec![],
+                })
+            }
+        };
 
-        println!(
+        debug!(
             "Reading directory with name {} at 0x{:X}",
             name,
-            partition.block_offset(entry.block_chain[0]),
+            partition.block_offset(first_cluster),
         );
 
-        let entries: Vec<Entry> = entry
-            .block_chain
-            .iter()
-            .flat_map(|block| Self::read_block(partition, *block).expect("failed to read block"))
-            .collect();
+        let mut entries = vec![];
+        for block in entry.block_chain.iter() {
+            entries.extend(Self::read_block(partition, block)?);
+        }
 
-        Ok(Directory {
-            name: name,
-            entries,
-        })
+        Ok(Directory { name, entries })
     }
 
     pub fn read_block(partition: &Partition, block: usize) -> Result<Vec<Entry>, io::Error> {
-        let mut entry_data: [u8; 0x40] = [0u8; 0x40];
+        let mut entry_data: [u8; ENTRY_SIZE] = [0u8; ENTRY_SIZE];
         let block_size = partition.cluster_size();
         let block_offset = partition.block_offset(block);
 
@@ -288,7 +804,7 @@ impl Directory {
         while cursor.position() < block_offset + block_size as u64 {
             let offset = cursor.position();
             debug!("Reading entry at 0x{:X}", offset);
-            cursor.read(&mut entry_data)?;
+            cursor.read_exact(&mut entry_data)?;
 
             let entry = Entry::parse(partition, &entry_data, offset)?;
             match entry {
@@ -309,6 +825,8 @@ impl Directory {
     }
 }
 
+/// A directory entry describing a file or directory. Deleted entries are
+/// included since they can often still be recovered.
 #[derive(Debug, Clone)]
 pub struct Entry {
     offset: u64,
@@ -317,7 +835,10 @@ pub struct Entry {
     size: usize,
     block: usize,
     attr: EntryAttributes,
-    block_chain: Vec<usize>,
+    block_chain: ClusterChain,
+    created: Option<NaiveDateTime>,
+    modified: Option<NaiveDateTime>,
+    accessed: Option<NaiveDateTime>,
 }
 
 impl Entry {
@@ -328,11 +849,17 @@ impl Entry {
             name: "".to_owned(),
             block: partition.root_cluster(),
             attr: EntryAttributes::DIRECTORY,
-            block_chain: partition.block_chain_from_root(partition.root_cluster())?,
+            block_chain: partition.block_chain_from_root(partition.root_cluster()),
             size: 0,
+            created: None,
+            modified: None,
+            accessed: None,
         })
     }
 
+    /// Parses the 0x40-byte directory entry in `data`, which was read from
+    /// `offset` on the disk. Returns `None` if the data doesn't look like a
+    /// valid entry.
     pub fn parse(
         partition: &Partition,
         data: &[u8],
@@ -358,19 +885,15 @@ impl Entry {
         debug!("Reading bytes corresponding to the name");
         // Read the maximum number of bytes in a filename
         let mut name_bytes: [u8; MAX_FILENAME_LEN] = [0u8; MAX_FILENAME_LEN];
-        cursor.read(&mut name_bytes)?;
+        cursor.read_exact(&mut name_bytes)?;
 
         // If the file is deleted we need to figure out the number of bytes that were actually in this filename
         if is_deleted {
             // figure out the file name len
-            for i in 0..MAX_FILENAME_LEN {
-                name_len = i as u8;
-
-                match name_bytes[i] {
-                    0xFF | 0x00 => break,
-                    _ => continue,
-                }
-            }
+            name_len = name_bytes
+                .iter()
+                .position(|b| *b == 0xFF || *b == 0x00)
+                .unwrap_or(MAX_FILENAME_LEN) as u8;
         }
 
         // Special case where the sequence is 0xe5 0x10 0x00
@@ -390,64 +913,63 @@ impl Entry {
 
         // Convert the name to a string
         let name = String::from_utf8_lossy(&name_bytes[0..name_len as usize]);
-        if name.len() == 0 {
+        if name.is_empty() {
             return Ok(None);
         }
-        println!("Parsed name: {}", name);
+        debug!("Parsed name: {}", name);
 
         debug!("Reading start block");
 
         // Read the start block
-        let block = cursor.read_u32::<BigEndian>()? as usize;
+        let block = partition.endian().read_u32(&mut cursor)? as usize;
         debug!("Reading file size");
         // Read the file size
-        let file_size = cursor.read_u32::<BigEndian>()? as usize;
+        let file_size = partition.endian().read_u32(&mut cursor)? as usize;
 
         // if the file's bigger than 4GB, ignore
         if file_size > 0x1024 * 0x1024 * 0x1024 * 4 {
             return Ok(None);
         }
 
-        // From here we ignore the rest of the data
+        debug!("Reading timestamps");
+        let epoch = partition.timestamp_epoch();
+        let created = decode_timestamp(partition.endian().read_u32(&mut cursor)?, epoch);
+        let modified = decode_timestamp(partition.endian().read_u32(&mut cursor)?, epoch);
+        let accessed = decode_timestamp(partition.endian().read_u32(&mut cursor)?, epoch);
 
         debug!("Reading block chain");
         // Read the block chain
-        let mut block_chain = if !is_deleted {
-            partition.block_chain_from_root(block)?
+        let block_chain = if !is_deleted {
+            partition.block_chain_from_root(block)
         } else {
-            let mut num_blocks = file_size / partition.cluster_size();
-            if file_size % partition.cluster_size() > 0 {
-                num_blocks += 1;
-            }
+            let num_blocks = file_size.div_ceil(partition.cluster_size());
 
             (block..=block + num_blocks).collect()
         };
         debug!("Entry block chain before filtering: {:?}", block_chain);
 
-        for block in &mut block_chain {
-            if *block == 0 {
-                continue;
-            }
-
-            if partition.block_offset(*block) > partition.data().len() as u64 {
-                *block = 0x0;
-            }
-        }
-
         // hax
-        block_chain.retain(|b| *b != 0);
+        let block_chain: ClusterChain = block_chain
+            .iter()
+            .filter(|block| {
+                *block != 0 && partition.block_offset(*block) <= partition.data().len() as u64
+            })
+            .collect();
 
         debug!("Returning parsed entry");
         debug!("Entry block chain: {:?}", block_chain);
 
         let parsed_entry = Entry {
             offset,
-            is_deleted: name_len == DELETED_FILE_FLAG,
+            is_deleted,
             name: name.to_string(),
             size: file_size,
             block,
             attr: EntryAttributes::from_bits(attributes).unwrap_or(EntryAttributes::NONE),
             block_chain,
+            created,
+            modified,
+            accessed,
         };
 
         Ok(Some(parsed_entry))
@@ -465,6 +987,34 @@ impl Entry {
         (self.attr & EntryAttributes::DIRECTORY) != EntryAttributes::NONE
     }
 
+    pub fn is_deleted(&self) -> bool {
+        self.is_deleted
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn block(&self) -> usize {
+        self.block
+    }
+
+    pub fn attributes(&self) -> EntryAttributes {
+        self.attr
+    }
+
+    pub fn created(&self) -> Option<NaiveDateTime> {
+        self.created
+    }
+
+    pub fn modified(&self) -> Option<NaiveDateTime> {
+        self.modified
+    }
+
+    pub fn accessed(&self) -> Option<NaiveDateTime> {
+        self.accessed
+    }
+
     pub fn write_to_file(&self, path: &Path, partition: &Partition) -> Result<(), io::Error> {
         if path.exists() {
             return Ok(());
@@ -474,38 +1024,214 @@ impl Entry {
             fs::create_dir(path.parent().unwrap())?;
         }
 
-        for block in self.block_chain() {
-            if *block == 0 {
-                return Ok(());
-            }
-
-            let block_offset = partition.block_offset(*block);
-            if block_offset > partition.offset() + partition.len() as u64
-                || block_offset > partition.data().len() as u64
+        // Check the whole chain before creating the file so that a broken
+        // chain doesn't leave half of a file behind. Clusters only get further
+        // into the disk within an extent, so only the end of each one needs to
+        // be checked.
+        let data_end = cmp::min(
+            partition.offset() + partition.len() as u64,
+            partition.data().len() as u64,
+        );
+        for extent in self.block_chain().extents() {
+            let last = extent.start + extent.len - 1;
+            if extent.start == 0
+                || partition.block_offset(last) + partition.cluster_size() as u64 > data_end
             {
-                return Ok(());
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "cluster chain of {} runs outside of the partition",
+                        self.name
+                    ),
+                ));
             }
         }
 
         let mut file = File::create(path)?;
+        if let Err(e) = self.write_to(&mut file, partition) {
+            drop(file);
+            let _ = fs::remove_file(path);
+            return Err(e);
+        }
 
-        let all_data: Vec<u8> = self
-            .block_chain
-            .iter()
-            .flat_map(|block| partition.block_data(*block).to_vec())
-            .take(self.size)
-            .collect();
+        self.apply_timestamps(path)?;
 
-        file.write(&all_data)?;
+        Ok(())
+    }
+
+    /// Writes the file's data to `writer`
+    pub fn write_to<W: Write>(
+        &self,
+        writer: &mut W,
+        partition: &Partition,
+    ) -> Result<(), io::Error> {
+        io::copy(&mut self.reader(partition), writer)?;
 
         Ok(())
     }
 
-    pub fn block_chain(&self) -> &[usize] {
-        self.block_chain.as_slice()
+    /// Returns a reader over the file's data which streams directly from the
+    /// partition's clusters
+    pub fn reader<'p, 'a>(&'p self, partition: &'p Partition<'a>) -> EntryReader<'p, 'a> {
+        EntryReader {
+            partition,
+            block_chain: Cow::Borrowed(self.block_chain()),
+            size: self.size as u64,
+            position: 0,
+        }
+    }
+
+    /// Like `reader`, but takes ownership of the entry so that the reader can
+    /// outlive the directory it came from
+    pub fn into_reader<'p, 'a>(self, partition: &'p Partition<'a>) -> EntryReader<'p, 'a> {
+        EntryReader {
+            partition,
+            block_chain: Cow::Owned(self.block_chain),
+            size: self.size as u64,
+            position: 0,
+        }
+    }
+
+    /// Sets the access and modification times of the file at `path` to this
+    /// entry's timestamps. The console doesn't store a timezone so these are
+    /// treated as UTC.
+    pub fn apply_timestamps(&self, path: &Path) -> Result<(), io::Error> {
+        let to_file_time =
+            |time: NaiveDateTime| FileTime::from_unix_time(time.and_utc().timestamp(), 0);
+
+        match (self.accessed, self.modified) {
+            (Some(accessed), Some(modified)) => {
+                filetime::set_file_times(path, to_file_time(accessed), to_file_time(modified))
+            }
+            (None, Some(modified)) => filetime::set_file_mtime(path, to_file_time(modified)),
+            (Some(accessed), None) => filetime::set_file_atime(path, to_file_time(accessed)),
+            (None, None) => Ok(()),
+        }
+    }
+
+    pub fn block_chain(&self) -> &ClusterChain {
+        &self.block_chain
     }
 }
 
+/// Implements `Read` and `Seek` over an entry's data by mapping logical file
+/// offsets onto the entry's cluster chain. Reads stop at the entry's size.
+pub struct EntryReader<'p, 'a> {
+    partition: &'p Partition<'a>,
+    block_chain: Cow<'p, ClusterChain>,
+    size: u64,
+    position: u64,
+}
+
+impl EntryReader<'_, '_> {
+    pub fn len(&self) -> u64 {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+}
+
+impl Read for EntryReader<'_, '_> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        if self.position >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let cluster_size = self.partition.cluster_size() as u64;
+        let chain_index = (self.position / cluster_size) as usize;
+        let cluster_offset = self.position % cluster_size;
+
+        let block = match self.block_chain.get(chain_index) {
+            Some(block) => block,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "cluster chain ended before the end of the file",
+                ))
+            }
+        };
+
+        let data = self.partition.data();
+        let start = self.partition.block_offset(block) + cluster_offset;
+        let end = cmp::min(
+            self.partition.block_offset(block) + cluster_size,
+            data.len() as u64,
+        );
+        if start >= end {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("cluster 0x{:X} is outside of the disk", block),
+            ));
+        }
+
+        let len = cmp::min(
+            cmp::min(buf.len() as u64, end - start),
+            self.size - self.position,
+        ) as usize;
+        buf[..len].copy_from_slice(&data[start as usize..start as usize + len]);
+        self.position += len as u64;
+
+        Ok(len)
+    }
+}
+
+impl Seek for EntryReader<'_, '_> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, io::Error> {
+        let new_position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+
+        match new_position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+/// Decodes a packed FAT timestamp where the upper 16 bits are the date and the
+/// lower 16 bits are the time. Returns `None` for zeroed or invalid timestamps.
+pub fn decode_timestamp(packed: u32, epoch: i32) -> Option<NaiveDateTime> {
+    if packed == 0 {
+        return None;
+    }
+
+    let date = packed >> 16;
+    let time = packed & 0xFFFF;
+
+    let year = epoch + (date >> 9) as i32;
+    let month = (date >> 5) & 0xF;
+    let day = date & 0x1F;
+
+    let hour = time >> 11;
+    let minute = (time >> 5) & 0x3F;
+    let second = (time & 0x1F) * 2;
+
+    NaiveDate::from_ymd_opt(year, month, day)?.and_hms_opt(hour, minute, second)
+}
+
+/// Converts a Windows FILETIME (100ns intervals since 1601), as used by disc
+/// images and profiles, to a timestamp. Returns `None` for zeroed timestamps.
+pub fn decode_filetime(filetime: u64) -> Option<NaiveDateTime> {
+    if filetime == 0 {
+        return None;
+    }
+
+    NaiveDate::from_ymd_opt(1601, 1, 1)?
+        .and_hms_opt(0, 0, 0)?
+        .checked_add_signed(Duration::microseconds((filetime / 10) as i64))
+}
+
+/// Reads the devkit header at the start of the disk
 pub fn devkit_partitions<'a>(data: &'a [u8]) -> Result<DevkitHddInfo<'a>, DiskError> {
     const MIN_DISK_LENGTH: usize = 0x18;
 
@@ -541,3 +1267,453 @@ pub fn devkit_partitions<'a>(data: &'a [u8]) -> Result<DevkitHddInfo<'a>, DiskEr
 
     Ok(info)
 }
+
+/// Looks for partitions at the fixed offsets used by retail drives
+pub fn retail_partitions<'a>(data: &'a [u8]) -> Result<RetailHddInfo<'a>, DiskError> {
+    let mut partitions = vec![];
+
+    for (offset, len, name) in RETAIL_PARTITIONS.iter() {
+        if *offset as usize >= data.len() {
+            break;
+        }
+
+        let len = len.unwrap_or(data.len() - *offset as usize);
+
+        // Not every retail partition is formatted (e.g. SysExt2 on older
+        // dashboards), so only keep the ones that have a valid header
+        match Partition::new(data, *offset, len, name) {
+            Ok(partition) => partitions.push(partition),
+            Err(e) => debug!("Skipping retail partition {}: {}", name, e),
+        }
+    }
+
+    if partitions.is_empty() {
+        return Err(DiskError::NoPartitionsFound);
+    }
+
+    Ok(RetailHddInfo { partitions })
+}
+
+/// Sweeps the disk for XTAF/FATX superblocks on sector boundaries. This is useful
+/// for damaged disks or partial dumps where neither the devkit header nor the
+/// retail layout can be trusted.
+///
+/// Each partition is assumed to extend up to the next discovered partition
+/// (or the end of the disk).
+pub fn scan_partitions<'a>(data: &'a [u8]) -> Result<Vec<Partition<'a>>, DiskError> {
+    let mut candidates = vec![];
+
+    let mut offset = 0;
+    while offset + SECTOR_SIZE <= data.len() {
+        if let Some(endian) = Endian::from_magic(&data[offset..offset + 0x4]) {
+            let sectors_per_cluster = endian.read_u32(&mut &data[offset + 0x8..])?;
+            let root_dir_cluster = endian.read_u32(&mut &data[offset + 0xC..])?;
+
+            if sectors_per_cluster.is_power_of_two()
+                && sectors_per_cluster <= MAX_SECTORS_PER_CLUSTER
+                && root_dir_cluster != 0
+            {
+                debug!("Found partition candidate at 0x{:X}", offset);
+                candidates.push((offset, sectors_per_cluster, root_dir_cluster));
+            } else {
+                debug!("Ignoring insane superblock at 0x{:X}", offset);
+            }
+        }
+
+        offset += SECTOR_SIZE;
+    }
+
+    // Walk backwards so that a rejected candidate doesn't truncate the
+    // partition that precedes it
+    let mut partitions = vec![];
+    let mut end_offset = data.len();
+    for (offset, sectors_per_cluster, root_dir_cluster) in candidates.into_iter().rev() {
+        let len = end_offset - offset;
+        let cluster_size = sectors_per_cluster as usize * SECTOR_SIZE;
+
+        // The root directory must at least fit inside of the partition
+        if FAT_TABLE_OFFSET + root_dir_cluster as usize * cluster_size > len {
+            debug!(
+                "Root directory cluster 0x{:X} is outside of partition at 0x{:X}",
+                root_dir_cluster, offset
+            );
+            continue;
+        }
+
+        let name = format!("Partition_{:X}", offset);
+        match Partition::new(data, offset as u64, len, &name) {
+            Ok(partition) => {
+                partitions.push(partition);
+                end_offset = offset;
+            }
+            Err(e) => debug!("Ignoring partition candidate at 0x{:X}: {}", offset, e),
+        }
+    }
+
+    if partitions.is_empty() {
+        return Err(DiskError::NoPartitionsFound);
+    }
+
+    partitions.reverse();
+
+    // Disk images stored as files show up as partitions of their own, which
+    // cut off the partition that holds them. Drop any partition that starts
+    // inside an allocated cluster of the one before it.
+    let mut merged: Vec<Partition<'a>> = Vec::with_capacity(partitions.len());
+    for partition in partitions {
+        if let Some(previous) = merged.last_mut() {
+            let len = previous.len() + partition.len();
+            if let Ok(combined) = Partition::new(data, previous.offset(), len, previous.name()) {
+                let allocated = combined
+                    .cluster_at(partition.offset())
+                    .and_then(|cluster| combined.fat().get(cluster))
+                    .is_some_and(|next| next != 0);
+                if allocated {
+                    debug!(
+                        "Ignoring partition candidate at 0x{:X} inside of {}",
+                        partition.offset(),
+                        previous.name()
+                    );
+                    *previous = combined;
+                    continue;
+                }
+            }
+        }
+
+        merged.push(partition);
+    }
+
+    Ok(merged)
+}
+
+/// Detects the partition layout of the disk, trying the devkit header first
+/// and falling back to the fixed retail layout. If neither of those work the
+/// disk is scanned for partitions.
+pub fn hdd_partitions<'a>(data: &'a [u8]) -> Result<HddInfo<'a>, DiskError> {
+    match devkit_partitions(data) {
+        Ok(info) => return Ok(HddInfo::Devkit(info)),
+        Err(e) => debug!("Disk does not have a devkit layout: {}", e),
+    }
+
+    match retail_partitions(data) {
+        Ok(info) => return Ok(HddInfo::Retail(info)),
+        Err(e) => debug!("Disk does not have a retail layout: {}", e),
+    }
+
+    info!("Could not find a known partition layout, scanning for partitions");
+
+    scan_partitions(data).map(HddInfo::Discovered)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    const TEST_CLUSTER_SIZE: usize = 0x200;
+    const TEST_DATA_OFFSET: usize = 0x2000;
+
+    /// Builds a big-endian partition with one sector per cluster whose FAT
+    /// holds the given links
+    fn partition_image(links: &[(usize, u16)]) -> Vec<u8> {
+        let mut data = vec![0u8; 0x20000];
+        data[..4].copy_from_slice(b"XTAF");
+        data[0x8..0xC].copy_from_slice(&1u32.to_be_bytes());
+        data[0xC..0x10].copy_from_slice(&1u32.to_be_bytes());
+
+        for &(cluster, next) in links {
+            let offset = 0x1000 + cluster * 2;
+            data[offset..offset + 2].copy_from_slice(&next.to_be_bytes());
+        }
+
+        data
+    }
+
+    /// 11 January 2025 at 12:00:30 on a big-endian partition
+    const TEST_TIMESTAMP: u32 = (((45 << 9) | (1 << 5) | 11) << 16) | (12 << 11) | 15;
+
+    /// Writes a directory entry stamped with `TEST_TIMESTAMP` to the `slot`th
+    /// entry of `cluster` in a `partition_image`
+    fn write_dirent(
+        data: &mut [u8],
+        (cluster, slot): (usize, usize),
+        name: &str,
+        attr: EntryAttributes,
+        first_cluster: u32,
+        size: u32,
+    ) {
+        let offset = TEST_DATA_OFFSET + (cluster - 1) * TEST_CLUSTER_SIZE + slot * ENTRY_SIZE;
+        let entry = &mut data[offset..offset + ENTRY_SIZE];
+        entry[0] = name.len() as u8;
+        entry[1] = attr.bits();
+        entry[2..2 + MAX_FILENAME_LEN]
+            .iter_mut()
+            .for_each(|b| *b = 0xFF);
+        entry[2..2 + name.len()].copy_from_slice(name.as_bytes());
+        entry[0x2C..0x30].copy_from_slice(&first_cluster.to_be_bytes());
+        entry[0x30..0x34].copy_from_slice(&size.to_be_bytes());
+        for timestamp in entry[0x34..0x40].chunks_mut(4) {
+            timestamp.copy_from_slice(&TEST_TIMESTAMP.to_be_bytes());
+        }
+    }
+
+    /// A partition holding `/Content/Sub.bin`, `/hello.txt` and the deleted
+    /// file `/gone.txt`
+    pub(crate) fn directory_image() -> Vec<u8> {
+        let mut data = partition_image(&[(1, 0xFFFF), (2, 0xFFFF), (3, 0xFFFF), (5, 0xFFFF)]);
+        let file = EntryAttributes::empty();
+        write_dirent(
+            &mut data,
+            (1, 0),
+            "Content",
+            EntryAttributes::DIRECTORY,
+            2,
+            0,
+        );
+        write_dirent(&mut data, (1, 1), "hello.txt", file, 3, 5);
+        write_dirent(&mut data, (1, 2), "gone.txt", file, 4, 5);
+        data[TEST_DATA_OFFSET + 2 * ENTRY_SIZE] = DELETED_FILE_FLAG;
+        write_dirent(&mut data, (2, 0), "Sub.bin", file, 5, 0x10);
+        data
+    }
+
+    fn test_entry(name: &str, clusters: &[usize], size: usize) -> Entry {
+        Entry {
+            offset: 0,
+            is_deleted: false,
+            name: name.to_owned(),
+            size,
+            block: clusters[0],
+            attr: EntryAttributes::empty(),
+            block_chain: clusters.iter().copied().collect(),
+            created: None,
+            modified: None,
+            accessed: None,
+        }
+    }
+
+    fn file_entry(name: &str, clusters: &[usize]) -> (String, Entry) {
+        (format!("/{}", name), test_entry(name, clusters, 0))
+    }
+
+    #[test]
+    fn cluster_chain_merges_consecutive_clusters() {
+        let chain: ClusterChain = vec![5, 6, 7, 10, 11, 3].into_iter().collect();
+
+        assert_eq!(chain.len(), 6);
+        assert_eq!(chain.extents().len(), 3);
+        assert_eq!(chain.first(), Some(5));
+        assert_eq!(chain.get(0), Some(5));
+        assert_eq!(chain.get(2), Some(7));
+        assert_eq!(chain.get(3), Some(10));
+        assert_eq!(chain.get(5), Some(3));
+        assert_eq!(chain.get(6), None);
+        assert_eq!(chain.iter().collect::<Vec<_>>(), vec![5, 6, 7, 10, 11, 3]);
+    }
+
+    #[test]
+    fn block_chain_from_root_follows_the_fat() {
+        let data = partition_image(&[(1, 2), (2, 4), (4, 0xFFFF)]);
+        let partition = Partition::new(&data, 0, data.len(), "test").unwrap();
+
+        let chain = partition.block_chain_from_root(1);
+        assert_eq!(chain.iter().collect::<Vec<_>>(), vec![1, 2, 4]);
+    }
+
+    #[test]
+    fn block_chain_from_root_stops_at_loops() {
+        let data = partition_image(&[(1, 2), (2, 3), (3, 2)]);
+        let partition = Partition::new(&data, 0, data.len(), "test").unwrap();
+
+        let chain = partition.block_chain_from_root(1);
+        assert_eq!(chain.iter().collect::<Vec<_>>(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn lookup_matches_components_case_insensitively() {
+        let data = directory_image();
+        let partition = Partition::new(&data, 0, data.len(), "test").unwrap();
+
+        let entry = partition.lookup("/content/SUB.BIN").unwrap();
+        assert_eq!((entry.name(), entry.size()), ("Sub.bin", 0x10));
+        assert!(partition.lookup("/").unwrap().is_dir());
+
+        let names = |dir: Directory| -> Vec<String> {
+            dir.entries().iter().map(|e| e.name().to_owned()).collect()
+        };
+        assert_eq!(
+            names(partition.read_dir("/").unwrap()),
+            vec!["Content", "hello.txt", "gone.txt"]
+        );
+        assert_eq!(
+            names(partition.read_dir("Content").unwrap()),
+            vec!["Sub.bin"]
+        );
+    }
+
+    #[test]
+    fn lookup_reports_missing_components() {
+        let data = directory_image();
+        let partition = Partition::new(&data, 0, data.len(), "test").unwrap();
+
+        for path in ["Content/missing", "missing/Sub.bin", "gone.txt"].iter() {
+            assert!(
+                matches!(partition.lookup(path), Err(DiskError::PathNotFound(_))),
+                "{}",
+                path
+            );
+        }
+        assert!(matches!(
+            partition.lookup("hello.txt/Sub.bin"),
+            Err(DiskError::NotADirectory(_))
+        ));
+        assert!(matches!(
+            partition.read_dir("hello.txt"),
+            Err(DiskError::NotADirectory(_))
+        ));
+    }
+
+    #[test]
+    fn retail_partitions_are_at_fixed_offsets() {
+        // Only the system cache fits in the image
+        let mut data = vec![0u8; 0x90000];
+        data[0x80000..0x80010].copy_from_slice(&partition_image(&[])[..0x10]);
+
+        let info = retail_partitions(&data).unwrap();
+        let partitions: Vec<_> = info
+            .partitions()
+            .iter()
+            .map(|p| (p.name(), p.offset(), p.len()))
+            .collect();
+        assert_eq!(partitions, vec![("SystemCache", 0x80000, 0x8000_0000)]);
+        assert!(matches!(hdd_partitions(&data), Ok(HddInfo::Retail(_))));
+
+        // The partitions after the caches are laid out back to back, with the
+        // content partition taking up the rest of the disk
+        for pair in RETAIL_PARTITIONS[2..].windows(2) {
+            let (offset, len, _) = pair[0];
+            assert_eq!(offset + len.unwrap() as u64, pair[1].0);
+        }
+        assert_eq!(RETAIL_PARTITIONS[5], (0x1_30EB_0000, None, "Content"));
+
+        data[0x80000..0x80004].copy_from_slice(&[0; 4]);
+        assert!(matches!(
+            retail_partitions(&data),
+            Err(DiskError::NoPartitionsFound)
+        ));
+    }
+
+    #[test]
+    fn scan_partitions_skips_images_stored_in_files() {
+        // A copy of the superblock in cluster 5, which is either allocated to
+        // a file or free
+        for (next, expected) in [
+            (0xFFFF, vec![(0, 0x20000)]),
+            (0, vec![(0, 0x2800), (0x2800, 0x1D800)]),
+        ]
+        .iter()
+        {
+            let mut data = partition_image(&[(1, 0xFFFF), (5, *next)]);
+            data.copy_within(..0x10, 0x2800);
+
+            let partitions = scan_partitions(&data).unwrap();
+            let extents: Vec<_> = partitions.iter().map(|p| (p.offset(), p.len())).collect();
+            assert_eq!(&extents, expected);
+        }
+    }
+
+    #[test]
+    fn decode_timestamp_uses_epoch() {
+        // 11 January, 45 years after the epoch, at 12:00:30
+        let packed = (((45 << 9) | (1 << 5) | 11) << 16) | (12 << 11) | 15;
+
+        assert_eq!(
+            decode_timestamp(packed, 1980),
+            NaiveDate::from_ymd_opt(2025, 1, 11).and_then(|d| d.and_hms_opt(12, 0, 30))
+        );
+        assert_eq!(
+            decode_timestamp(packed, 2000),
+            NaiveDate::from_ymd_opt(2045, 1, 11).and_then(|d| d.and_hms_opt(12, 0, 30))
+        );
+        assert_eq!(decode_timestamp(0, 2000), None);
+        // Month 13
+        assert_eq!(decode_timestamp(((13 << 5) | 1) << 16, 2000), None);
+    }
+
+    #[test]
+    fn entry_reader_crosses_extents() {
+        let mut data = partition_image(&[]);
+        for (cluster, fill) in [(3, b'a'), (4, b'b'), (8, b'c')].iter() {
+            let offset = TEST_DATA_OFFSET + (cluster - 1) * TEST_CLUSTER_SIZE;
+            data[offset..offset + TEST_CLUSTER_SIZE]
+                .iter_mut()
+                .for_each(|b| *b = *fill);
+        }
+        let partition = Partition::new(&data, 0, data.len(), "test").unwrap();
+
+        let entry = test_entry("file", &[3, 4, 8], 0x500);
+        let mut reader = entry.reader(&partition);
+
+        let mut contents = vec![];
+        reader.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents.len(), 0x500);
+        assert!(contents[..0x200].iter().all(|b| *b == b'a'));
+        assert!(contents[0x200..0x400].iter().all(|b| *b == b'b'));
+        assert!(contents[0x400..].iter().all(|b| *b == b'c'));
+
+        let mut buf = [0u8; 0x20];
+        reader.seek(SeekFrom::Start(0x3F0)).unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..0x10], &[b'b'; 0x10]);
+        assert_eq!(&buf[0x10..], &[b'c'; 0x10]);
+
+        reader.seek(SeekFrom::End(-1)).unwrap();
+        assert_eq!(reader.read(&mut buf).unwrap(), 1);
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn write_to_file_rejects_chains_outside_the_partition() {
+        let data = partition_image(&[]);
+        let partition = Partition::new(&data, 0, data.len(), "test").unwrap();
+        let path = std::env::temp_dir().join(format!("fatx-test-{}", std::process::id()));
+
+        let last_cluster = partition.cluster_count();
+        for clusters in [vec![3, 0], vec![3, last_cluster + 1]].iter() {
+            let entry = test_entry("file", clusters, 0x400);
+            let err = entry.write_to_file(&path, &partition).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(!path.exists());
+        }
+    }
+
+    #[test]
+    fn cluster_index_finds_owners() {
+        let index = ClusterIndex::from_entries(vec![
+            file_entry("a", &[2, 3, 4, 10]),
+            file_entry("b", &[6, 7]),
+        ]);
+
+        let owner = index.owner(10).unwrap();
+        assert_eq!(owner.path, "/a");
+        assert_eq!(owner.chain_index, 3);
+        assert_eq!(index.owner(7).unwrap().path, "/b");
+        assert!(index.owner(1).is_none());
+        assert!(index.owner(5).is_none());
+        assert!(index.owner(11).is_none());
+    }
+
+    #[test]
+    fn cluster_index_finds_owners_inside_overlapping_extents() {
+        // `b` is cross-linked into the middle of `a`, so the extent starting
+        // closest to cluster 8 ends before it
+        let index = ClusterIndex::from_entries(vec![
+            file_entry("a", &[2, 3, 4, 5, 6, 7, 8, 9]),
+            file_entry("b", &[4, 5]),
+        ]);
+
+        let owner = index.owner(8).unwrap();
+        assert_eq!(owner.path, "/a");
+        assert_eq!(owner.chain_index, 6);
+        assert!(index.owner(10).is_none());
+    }
+}
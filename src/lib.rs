@@ -0,0 +1,49 @@
+//! Forensic parser for Xbox and Xbox 360 hard drive images.
+//!
+//! The [`fatx`] module understands the devkit and retail Xbox 360 partition
+//! layouts as well as the big-endian (XTAF) and little-endian (FATX) flavors
+//! of the filesystem. The [`scanners`] module carves deleted FATX entries and
+//! known file formats out of a partition using the [`carvers`] registered
+//! with it. Files found on the disk can be opened with the format modules,
+//! such as [`stfs`] for content packages, [`xdbf`] for profiles and
+//! achievements, [`xdvdfs`] for game disc images and [`xex`] for executables.
+//!
+//! ```no_run
+//! use blackhawkdown::fatx;
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let image = std::fs::read("xbox360.img")?;
+//! let hdd_info = fatx::hdd_partitions(&image)?;
+//!
+//! for partition in hdd_info.partitions() {
+//!     for entry in partition.read_dir("/")?.entries() {
+//!         println!("{}/{}", partition.name(), entry.name());
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+#[macro_use]
+extern crate bitflags;
+#[macro_use]
+extern crate log;
+
+pub mod bink;
+pub mod carvers;
+pub mod errors;
+pub mod fatx;
+pub mod scanners;
+pub mod stfs;
+pub mod timeline;
+pub mod xdbf;
+pub mod xdvdfs;
+pub mod xex;
+
+pub use crate::errors::{DiskError, FormatError};
+pub use crate::fatx::{
+    AllocationBitmap, ClusterIndex, Directory, Entry, EntryReader, HddInfo, Partition,
+};
+pub use crate::scanners::{
+    find_deleted_files, CarvedFile, Carver, CarverRegistry, DeletedFileType, ScanMode,
+};
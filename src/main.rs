@@ -1,30 +1,118 @@
-#![feature(thread_spawn_unchecked)]
-
-#[macro_use]
-extern crate bitflags;
-#[macro_use]
-extern crate log;
-
-use byteorder::{BigEndian, ByteOrder, LittleEndian, ReadBytesExt};
+use blackhawkdown::{carvers, errors, fatx, scanners, stfs, timeline, xdbf, xdvdfs, xex};
+use chrono::NaiveDateTime;
+use glob::{MatchOptions, Pattern};
 use memmap::MmapOptions;
 use std::cmp;
+use std::collections::HashSet;
 use std::fs::{self, File};
-use std::io::{self, Cursor, Seek, SeekFrom, Write};
+use std::io::{self, Read, Seek, Write};
 use std::path::Path;
 use std::path::PathBuf;
 use structopt::StructOpt;
 
-mod errors;
-mod fatx;
-mod scanners;
-
 #[derive(Debug, StructOpt)]
 struct Opt {
     #[structopt(parse(from_os_str))]
     input: PathBuf,
 
-    #[structopt(parse(from_os_str))]
-    output: PathBuf,
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Prints the disk header and partition geometry
+    #[structopt(name = "info")]
+    Info,
+
+    /// Lists the contents of a directory. Paths start with the partition
+    /// name (e.g. `Content/Content/0000000000000000`) and can continue into
+    /// XDVDFS disc images and SVOD packages.
+    #[structopt(name = "ls")]
+    Ls {
+        #[structopt(default_value = "/")]
+        path: String,
+    },
+
+    /// Writes a single file to stdout, which may be inside of a disc image
+    #[structopt(name = "cat")]
+    Cat { path: String },
+
+    /// Extracts files whose path matches any of the given paths or glob
+    /// patterns. Everything is extracted if no filters are provided.
+    #[structopt(name = "extract")]
+    Extract {
+        #[structopt(parse(from_os_str))]
+        output: PathBuf,
+
+        filters: Vec<String>,
+
+        /// Also extract the contents of XDVDFS disc images and SVOD packages
+        #[structopt(long = "images")]
+        images: bool,
+    },
+
+    /// Scans unallocated space for deleted files and known file formats
+    #[structopt(name = "carve")]
+    Carve {
+        #[structopt(parse(from_os_str))]
+        output: PathBuf,
+
+        /// Size that files with no length (e.g. JPEGs without an end marker)
+        /// are carved up to. Accepts decimal or 0x-prefixed hex.
+        #[structopt(long = "max-carve-size", parse(try_from_str = "parse_number"))]
+        max_carve_size: Option<u64>,
+
+        /// Also scan clusters that belong to live files. Files found inside
+        /// of them are reported along with the file that they're part of.
+        #[structopt(long = "all-clusters")]
+        all_clusters: bool,
+    },
+
+    /// Writes a Sleuth Kit bodyfile of every live and recovered entry
+    #[structopt(name = "timeline")]
+    Timeline {
+        #[structopt(parse(from_os_str))]
+        output: PathBuf,
+    },
+
+    /// Rebuilds the PE image embedded in an unencrypted XEX2 file
+    #[structopt(name = "basefile")]
+    Basefile {
+        path: String,
+
+        #[structopt(parse(from_os_str))]
+        output: PathBuf,
+    },
+
+    /// Lists the files inside of an STFS package (CON, LIVE or PIRS) and
+    /// extracts them if an output directory is given
+    #[structopt(name = "stfs")]
+    Stfs {
+        path: String,
+
+        #[structopt(parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+
+    /// Reports what a disk offset belongs to: a file (and the offset into
+    /// it), a directory, the FAT, or free space. Accepts decimal or
+    /// 0x-prefixed hex.
+    #[structopt(name = "whatis")]
+    Whatis {
+        #[structopt(parse(try_from_str = "parse_number"))]
+        offset: u64,
+    },
+
+    /// Prints the records in an XDBF file (e.g. a GPD). If `inner_path` is
+    /// given, `path` is an STFS package such as a profile and the XDBF file
+    /// is read from inside of it.
+    #[structopt(name = "xdbf")]
+    Xdbf {
+        path: String,
+
+        inner_path: Option<String>,
+    },
 }
 
 fn main() -> Result<(), io::Error> {
@@ -35,165 +123,1169 @@ fn main() -> Result<(), io::Error> {
     let input_file = File::open(opt.input)?;
     let mmap = unsafe { MmapOptions::new().map(&input_file)? };
 
-    let devkit_info = fatx::devkit_partitions(&mmap).unwrap();
-    println!("{:#X?}", devkit_info);
+    let hdd_info = fatx::hdd_partitions(&mmap).map_err(disk_error)?;
+
+    match opt.command {
+        Command::Info => {
+            print_info(&hdd_info);
+            Ok(())
+        }
+        Command::Ls { path } => list_dir(hdd_info.partitions(), &path),
+        Command::Cat { path } => cat_file(hdd_info.partitions(), &path),
+        Command::Extract {
+            output,
+            filters,
+            images,
+        } => extract(hdd_info.partitions(), &output, &filters, images),
+        Command::Carve {
+            output,
+            max_carve_size,
+            all_clusters,
+        } => {
+            let carvers = carvers::default_registry(
+                max_carve_size.unwrap_or(carvers::DEFAULT_MAX_CARVE_SIZE),
+            );
+            let mode = if all_clusters {
+                scanners::ScanMode::All
+            } else {
+                scanners::ScanMode::Unallocated
+            };
+            carve(&mmap, hdd_info.partitions(), &carvers, mode, &output)
+        }
+        Command::Timeline { output } => write_timeline(&mmap, hdd_info.partitions(), &output),
+        Command::Basefile { path, output } => {
+            extract_basefile(hdd_info.partitions(), &path, &output)
+        }
+        Command::Stfs { path, output } => {
+            open_stfs_package(hdd_info.partitions(), &path, output.as_deref())
+        }
+        Command::Whatis { offset } => whatis(hdd_info.partitions(), offset),
+        Command::Xdbf { path, inner_path } => {
+            open_xdbf_file(hdd_info.partitions(), &path, inner_path.as_deref())
+        }
+    }
+}
+
+fn parse_number(number: &str) -> Result<u64, std::num::ParseIntError> {
+    match number
+        .strip_prefix("0x")
+        .or_else(|| number.strip_prefix("0X"))
+    {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => number.parse(),
+    }
+}
+
+fn disk_error(error: errors::DiskError) -> io::Error {
+    let kind = match error {
+        errors::DiskError::IoError(e) => return e,
+        errors::DiskError::PathNotFound(_) => io::ErrorKind::NotFound,
+        _ => io::ErrorKind::InvalidData,
+    };
+
+    io::Error::new(kind, error.to_string())
+}
+
+fn format_error(error: errors::FormatError) -> io::Error {
+    match error {
+        errors::FormatError::IoError(e) => e,
+        _ => io::Error::new(io::ErrorKind::InvalidData, error.to_string()),
+    }
+}
+
+fn print_info(hdd_info: &fatx::HddInfo) {
+    match hdd_info {
+        fatx::HddInfo::Devkit(info) => {
+            let (major, minor, revision, patch) = info.version();
+            println!(
+                "Devkit disk (header version {}.{}.{}.{})",
+                major, minor, revision, patch
+            );
+        }
+        fatx::HddInfo::Retail(_) => println!("Retail disk"),
+        fatx::HddInfo::Discovered(_) => {
+            println!("Unknown disk layout (partitions discovered by scanning)")
+        }
+    }
+
+    for partition in hdd_info.partitions() {
+        println!();
+        println!("Partition {}", partition.name());
+        println!("  Offset:         0x{:X}", partition.offset());
+        println!("  Length:         0x{:X}", partition.len());
+        println!("  Byte order:     {:?}", partition.endian());
+        println!("  Cluster size:   0x{:X}", partition.cluster_size());
+        println!("  Cluster count:  0x{:X}", partition.cluster_count());
+        println!("  Root cluster:   0x{:X}", partition.root_cluster());
+        println!("  FAT offset:     0x{:X}", partition.fat_offset());
+        println!("  FAT entry size: {} bits", partition.fat_entry_size() * 8);
+        println!("  Data offset:    0x{:X}", partition.data_start());
+    }
+}
+
+/// Prints what the disk offset `offset` belongs to: a partition's header or
+/// FAT, free space, or the file or directory that owns its cluster
+fn whatis(partitions: &[fatx::Partition], offset: u64) -> Result<(), io::Error> {
+    let partition = partitions
+        .iter()
+        .find(|p| offset >= p.offset() && offset < p.offset() + p.len() as u64);
+    let partition = match partition {
+        Some(partition) => partition,
+        None => {
+            println!("0x{:X} is outside of any partition", offset);
+            return Ok(());
+        }
+    };
+
+    println!(
+        "Partition: {} (offset 0x{:X} into the partition)",
+        partition.name(),
+        offset - partition.offset()
+    );
+
+    if offset < partition.fat_offset() {
+        println!("Partition header");
+        return Ok(());
+    }
+
+    if offset < partition.data_start() {
+        let fat_entry = (offset - partition.fat_offset()) / partition.fat_entry_size() as u64;
+        println!("FAT table (entry for cluster 0x{:X})", fat_entry);
+        return Ok(());
+    }
+
+    let cluster = match partition.cluster_at(offset) {
+        Some(cluster) => cluster,
+        None => return Ok(()),
+    };
+    let cluster_offset = offset - partition.block_offset(cluster);
+    println!(
+        "Cluster:   0x{:X} (offset 0x{:X} into the cluster)",
+        cluster, cluster_offset
+    );
+
+    // Only one FAT entry is needed, so there's no point building the whole
+    // allocation bitmap
+    if matches!(partition.fat().get(cluster), None | Some(0)) {
+        println!("Free space");
+        return Ok(());
+    }
+
+    let index = fatx::ClusterIndex::build(partition)?;
+    let owner = match index.owner(cluster) {
+        Some(owner) => owner,
+        None => {
+            println!("Allocated, but not part of any live file or directory");
+            return Ok(());
+        }
+    };
+
+    let file_offset = (owner.chain_index * partition.cluster_size()) as u64 + cluster_offset;
+    if owner.entry.is_dir() {
+        println!(
+            "Directory: {} (entry {})",
+            owner.path,
+            file_offset / fatx::ENTRY_SIZE as u64
+        );
+    } else if file_offset < owner.entry.size() as u64 {
+        println!(
+            "File:      {} (offset 0x{:X} of 0x{:X} bytes)",
+            owner.path,
+            file_offset,
+            owner.entry.size()
+        );
+    } else {
+        println!(
+            "File:      {} (slack space 0x{:X} bytes past the end of the file)",
+            owner.path,
+            file_offset - owner.entry.size() as u64
+        );
+    }
+
+    Ok(())
+}
 
-    for partition in devkit_info.partitions() {
+/// Splits a path into the partition named by its first component and the
+/// path inside of that partition
+fn resolve_partition<'p, 'a, 's>(
+    partitions: &'p [fatx::Partition<'a>],
+    path: &'s str,
+) -> Result<(&'p fatx::Partition<'a>, &'s str), io::Error> {
+    let path = path.trim_start_matches('/');
+    let (partition_name, path) = match path.find('/') {
+        Some(idx) => (&path[..idx], &path[idx + 1..]),
+        None => (path, ""),
+    };
+
+    let partition = partitions
+        .iter()
+        .find(|p| p.name().eq_ignore_ascii_case(partition_name))
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no partition named \"{}\"", partition_name),
+            )
+        })?;
+
+    Ok((partition, path))
+}
+
+fn list_dir(partitions: &[fatx::Partition], path: &str) -> Result<(), io::Error> {
+    if path.split('/').all(|c| c.is_empty()) {
+        for partition in partitions {
+            println!("{}", partition.name());
+        }
+
+        return Ok(());
+    }
+
+    let (partition, path) = resolve_partition(partitions, path)?;
+    if let Some((file_path, image_path)) = split_at_file(partition, path)? {
+        let (entry, siblings) = lookup_with_siblings(partition, file_path)?;
+        let mut image = match open_disc_image(partition, &entry, siblings.entries()) {
+            Ok(image) => image,
+            Err(_) if image_path.is_empty() => {
+                print_entry(&entry);
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+
+        let entry = image.lookup(image_path).map_err(format_error)?;
+        if !entry.is_dir() {
+            print_image_entry(&entry);
+            return Ok(());
+        }
+
+        for entry in image.read_dir(&entry).map_err(format_error)? {
+            print_image_entry(&entry);
+        }
+
+        return Ok(());
+    }
+
+    let dir = partition.read_dir(path).map_err(disk_error)?;
+    for entry in dir.entries() {
+        print_entry(entry);
+    }
+
+    Ok(())
+}
+
+fn print_entry(entry: &fatx::Entry) {
+    println!(
+        "{} {:>10} {:<19} {}{}",
+        if entry.is_dir() { "d" } else { "-" },
+        entry.size(),
+        format_timestamp(entry.modified()),
+        entry.name(),
+        if entry.is_deleted() { " (deleted)" } else { "" },
+    );
+}
+
+fn print_image_entry(entry: &xdvdfs::XdvdfsEntry) {
+    println!(
+        "{} {:>10} {:<19} {}",
+        if entry.is_dir() { "d" } else { "-" },
+        entry.size(),
+        "-",
+        entry.name(),
+    );
+}
+
+fn cat_file(partitions: &[fatx::Partition], path: &str) -> Result<(), io::Error> {
+    let (partition, path) = resolve_partition(partitions, path)?;
+    if let Some((file_path, image_path)) = split_at_file(partition, path)? {
+        if !image_path.is_empty() {
+            let (entry, siblings) = lookup_with_siblings(partition, file_path)?;
+            let mut image = open_disc_image(partition, &entry, siblings.entries())?;
+            let entry = image.lookup(image_path).map_err(format_error)?;
+            if entry.is_dir() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} is a directory", path),
+                ));
+            }
+
+            let stdout = io::stdout();
+            let mut stdout = stdout.lock();
+            image
+                .write_file(&entry, &mut stdout)
+                .map_err(format_error)?;
+            return stdout.flush();
+        }
+    }
+
+    let entry = partition.lookup(path).map_err(disk_error)?;
+    if entry.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is a directory", path),
+        ));
+    }
+
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    entry.write_to(&mut stdout, partition)?;
+    stdout.flush()
+}
+
+fn extract(
+    partitions: &[fatx::Partition],
+    output: &Path,
+    filters: &[String],
+    images: bool,
+) -> Result<(), io::Error> {
+    let filters = filters
+        .iter()
+        .map(|filter| Pattern::new(filter.trim_matches('/')))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+
+    for partition in partitions {
         let dir = partition.root_dir()?;
         let path = Path::new(partition.name());
-        print_dir(&dir, &path, partition, &opt.output);
+        let mut visited = HashSet::new();
+        visited.insert(partition.root_cluster());
+        let options = ExtractOptions {
+            filters: &filters,
+            images,
+            include_deleted: false,
+        };
+        extract_dir(&dir, path, partition, output, &options, &mut visited)?;
     }
 
-    for partition in devkit_info.partitions() {
-        let all_entries: Vec<fatx::Entry> = partition
-            .root_dir()?
-            .entries()
-            .iter()
-            .flat_map(|entry| {
-                if entry.is_dir() {
-                    let dir = &fatx::Directory::parse(entry, partition, entry.name().to_owned())
-                        .expect("could not parse directory");
-                    dir.entries().to_vec()
-                } else {
-                    vec![entry.clone()]
-                }
-            })
-            .collect();
+    Ok(())
+}
 
-        let end_offset = cmp::min(partition.offset() as usize + partition.len(), mmap.len());
+/// Returns true if there are no filters or any filter matches the path or one
+/// of its parent directories
+fn matches_filters(path: &Path, filters: &[Pattern]) -> bool {
+    let options = MatchOptions {
+        case_sensitive: false,
+        require_literal_separator: true,
+        require_literal_leading_dot: false,
+    };
 
-        let deleted_files = scanners::find_deleted_files(
-            &mmap,
-            partition.offset(),
-            end_offset as u64,
-            &all_entries,
-            partition,
-        );
+    filters.is_empty()
+        || path
+            .ancestors()
+            .any(|path| filters.iter().any(|f| f.matches_path_with(path, options)))
+}
+
+fn carve_partition(
+    mmap: &[u8],
+    partition: &fatx::Partition,
+    carvers: &scanners::CarverRegistry,
+    mode: scanners::ScanMode,
+) -> Result<Vec<scanners::DeletedFileType>, io::Error> {
+    let index = fatx::ClusterIndex::build(partition)?;
 
-        println!("Done scanning for deleted files");
+    let end_offset = cmp::min(partition.offset() as usize + partition.len(), mmap.len());
 
-        let mut video_files = 0;
-        let mut stfs_files = 0;
+    let deleted_files = scanners::find_deleted_files(
+        mmap,
+        partition.offset(),
+        end_offset as u64,
+        &index,
+        partition,
+        carvers,
+        mode,
+    );
+
+    println!("Done scanning for deleted files");
+
+    Ok(deleted_files)
+}
+
+/// Name to recover a deleted entry as. Deleted entries often share a name,
+/// so the offset of the entry is added to keep them apart.
+fn deleted_entry_name(entry: &fatx::Entry) -> String {
+    let name = carvers::sanitize_file_name(entry.name());
+    match name.rfind('.') {
+        Some(idx) if idx > 0 => format!("{}_{:X}{}", &name[..idx], entry.offset(), &name[idx..]),
+        _ => format!("{}_{:X}", name, entry.offset()),
+    }
+}
+
+/// Prints how many of each kind of file a scan of `partition` turned up. The
+/// individual hits are logged at the debug level.
+fn print_carve_summary(partition: &fatx::Partition, deleted_files: &[scanners::DeletedFileType]) {
+    let (mut entries, mut carved, mut embedded) = (0, 0, 0);
+    for file in deleted_files {
+        match file {
+            scanners::DeletedFileType::FatxEntry(_) => entries += 1,
+            scanners::DeletedFileType::Carved(_) => carved += 1,
+            scanners::DeletedFileType::Embedded(..) => embedded += 1,
+        }
+    }
+
+    println!(
+        "{}: found {} deleted entries, {} carved files and {} files inside of live files",
+        partition.name(),
+        entries,
+        carved,
+        embedded
+    );
+}
+
+fn write_timeline(
+    mmap: &[u8],
+    partitions: &[fatx::Partition],
+    output: &Path,
+) -> Result<(), io::Error> {
+    let mut bodyfile = timeline::BodyfileWriter::new(io::BufWriter::new(File::create(output)?));
+    // Only deleted FATX entries go in the timeline, so there's no need to run
+    // any carvers
+    let carvers = scanners::CarverRegistry::new();
+
+    for partition in partitions {
+        bodyfile.write_partition(partition)?;
+
+        let deleted_files =
+            carve_partition(mmap, partition, &carvers, scanners::ScanMode::Unallocated)?;
+        bodyfile.write_recovered(partition, &deleted_files)?;
+    }
+
+    bodyfile.into_inner().flush()?;
+
+    println!("Wrote timeline to {}", output.display());
+
+    Ok(())
+}
+
+fn carve(
+    mmap: &[u8],
+    partitions: &[fatx::Partition],
+    carvers: &scanners::CarverRegistry,
+    mode: scanners::ScanMode,
+    output: &Path,
+) -> Result<(), io::Error> {
+    for partition in partitions {
+        let deleted_files = carve_partition(mmap, partition, carvers, mode)?;
+        print_carve_summary(partition, &deleted_files);
 
         for file in &deleted_files {
-            let path = opt.output.join(Path::new(partition.name()));
-            let deleted_files_path = opt.output.join("deleted_files");
+            let path = Path::new(partition.name());
+            let deleted_files_path = output.join("deleted_files");
             if !deleted_files_path.exists() {
-                fs::create_dir(&deleted_files_path);
+                fs::create_dir(&deleted_files_path)?;
             }
 
             match file {
+                // Already part of a live file, which `extract` covers
+                scanners::DeletedFileType::Embedded(..) => {}
                 scanners::DeletedFileType::FatxEntry(entry) => {
                     if entry.is_dir() {
-                        let dir =
-                            &fatx::Directory::parse(entry, partition, entry.name().to_owned())
-                                .expect("could not parse directory");
-
-                        print_dir(&dir, &path, partition, &deleted_files_path);
+                        // The children of a deleted directory are usually
+                        // deleted as well
+                        let options = ExtractOptions {
+                            filters: &[],
+                            images: false,
+                            include_deleted: true,
+                        };
+                        let result =
+                            fatx::Directory::parse(entry, partition, deleted_entry_name(entry))
+                                .and_then(|dir| {
+                                    extract_dir(
+                                        &dir,
+                                        path,
+                                        partition,
+                                        &deleted_files_path,
+                                        &options,
+                                        &mut HashSet::new(),
+                                    )
+                                });
+                        if let Err(e) = result {
+                            println!("Could not extract directory {}: {}", entry.name(), e);
+                        }
                     } else {
-                        entry.write_to_file(&opt.output.join(entry.name()), partition);
+                        let file_path = deleted_files_path.join(deleted_entry_name(entry));
+                        if let Err(e) = entry.write_to_file(&file_path, partition) {
+                            println!("Could not write {}: {}", file_path.display(), e);
+                        }
                     }
                 }
-                scanners::DeletedFileType::STFS(offset) => {
-                    // get the file name
-                    let mut chars: Vec<u16> = vec![];
-                    let mut cursor = Cursor::new(&mmap);
-                    cursor.seek(SeekFrom::Start(*offset + 0x411))?;
-
-                    for _i in (0..0x411).step_by(2) {
-                        let c = cursor.read_u16::<BigEndian>()?;
-                        if c == 0x0 {
-                            break;
+                scanners::DeletedFileType::Carved(file) => {
+                    let size = match file.size() {
+                        Some(size) => size,
+                        None => {
+                            println!(
+                                "Found {} file at 0x{:X} but its length is unknown",
+                                file.carver(),
+                                file.offset()
+                            );
+                            continue;
                         }
+                    };
 
-                        chars.push(c);
-                    }
-
-                    let display_name = String::from_utf16(chars.as_slice());
-                    if display_name.is_err() {
-                        continue;
-                    }
-
-                    let display_name = display_name.unwrap();
+                    let file_path = deleted_files_path.join(file.file_name());
                     println!(
-                        "Got STFS package at offset 0x{:X} with name: {}",
-                        offset, display_name
+                        "Writing {} file to {}{}",
+                        file.carver(),
+                        file_path.display(),
+                        if file.is_truncated() {
+                            " (truncated)"
+                        } else {
+                            ""
+                        }
                     );
 
-                    cursor.seek(SeekFrom::Start(*offset + 0x34c))?;
-                    let content_size = cursor.read_u64::<BigEndian>()?;
+                    let start = file.offset() as usize;
+                    let file_data = &mmap[start..start + size as usize];
+                    write_file_with_raw_bytes(&file_path, file_data)?;
+
+                    match file.carver() {
+                        "xex" => write_xex_basefile(file_data, &file_path.with_extension("exe")),
+                        "stfs" => {
+                            let contents_path =
+                                deleted_files_path.join(format!("{}_contents", file.file_name()));
+                            let mut cursor = io::Cursor::new(file_data);
+                            let result =
+                                stfs::XContentHeader::parse(&mut cursor).and_then(|header| {
+                                    print_stfs_metadata(&header);
+                                    write_stfs_thumbnails(
+                                        &header,
+                                        &deleted_files_path,
+                                        &format!("{}_", file.file_name()),
+                                    )?;
 
-                    cursor.seek(SeekFrom::Start(*offset + 0x37e))?;
-                    // just read from here and see if we hit some non-null data
-                    loop {
-                        let data = cursor.read_u32::<BigEndian>()?;
-                        if data != 0 {
-                            break;
+                                    if header.svod_volume_descriptor().is_some() {
+                                        println!("SVOD package data is stored in separate files");
+                                        return Ok(());
+                                    }
+
+                                    let mut package = stfs::StfsPackage::open(cursor)?;
+                                    print_stfs_verification(&mut package)?;
+                                    extract_stfs_package(&mut package, &contents_path)
+                                });
+
+                            if let Err(e) = result {
+                                println!("Could not extract STFS package contents: {}", e);
+                            }
+                        }
+                        "xdvdfs" => {
+                            let contents_path =
+                                deleted_files_path.join(format!("{}_contents", file.file_name()));
+                            let result =
+                                xdvdfs::XdvdfsVolume::open_at(io::Cursor::new(file_data), 0)
+                                    .and_then(|mut image| {
+                                        extract_image(&mut image, &contents_path)
+                                    });
+
+                            if let Err(e) = result {
+                                println!("Could not extract disc image contents: {}", e);
+                            }
+                        }
+                        "xdbf" => {
+                            if let Err(e) = print_xdbf_report(file_data) {
+                                println!("Could not parse XDBF file: {}", e);
+                            }
                         }
+                        _ => {}
                     }
+                }
+            }
+        }
+    }
 
-                    let content_start_offset = cursor.position() - 0x4;
+    Ok(())
+}
 
-                    if content_start_offset + content_size > mmap.len() as u64 {
-                        println!(
-                            "File has invalid length of 0x{:X} (start offset = 0x{:X})",
-                            content_size,
-                            content_start_offset - offset
-                        );
-                        continue;
-                    }
+fn extract_basefile(
+    partitions: &[fatx::Partition],
+    path: &str,
+    output: &Path,
+) -> Result<(), io::Error> {
+    let (partition, path) = resolve_partition(partitions, path)?;
+    let entry = partition.lookup(path).map_err(disk_error)?;
 
-                    let file_path = if display_name.is_empty() {
-                        stfs_files += 1;
-                        deleted_files_path.join(format!("unnamed_stfs_package_{}", stfs_files))
-                    } else {
-                        deleted_files_path.join(&display_name)
-                    };
+    let mut xex_data = vec![];
+    entry.reader(partition).read_to_end(&mut xex_data)?;
 
-                    println!("Writing STFS file to {}", file_path.display());
+    write_xex_basefile(&xex_data, output);
 
-                    write_file_with_raw_bytes(
-                        &file_path,
-                        &mmap[*offset as usize..(content_start_offset + content_size) as usize],
-                    )?;
+    Ok(())
+}
+
+fn open_stfs_package(
+    partitions: &[fatx::Partition],
+    path: &str,
+    output: Option<&Path>,
+) -> Result<(), io::Error> {
+    let (partition, path) = resolve_partition(partitions, path)?;
+    let entry = partition.lookup(path).map_err(disk_error)?;
+
+    let mut reader = entry.reader(partition);
+    let header = stfs::XContentHeader::parse(&mut reader).map_err(format_error)?;
+    print_stfs_metadata(&header);
+    if let Some(output) = output {
+        fs::create_dir_all(output)?;
+        write_stfs_thumbnails(&header, output, "")?;
+    }
+
+    if let Some(descriptor) = header.svod_volume_descriptor() {
+        return write_svod_image(partition, path, &header, descriptor, output);
+    }
+
+    let result = stfs::StfsPackage::open(reader).and_then(|mut package| {
+        print_stfs_verification(&mut package)?;
+        println!("{} files", package.files().len());
+
+        match output {
+            Some(output) => extract_stfs_package(&mut package, output),
+            None => {
+                for file in package.files() {
+                    println!(
+                        "{} {:>10} {:<19} {}",
+                        if file.is_dir() { "d" } else { "-" },
+                        file.size(),
+                        format_timestamp(file.created()),
+                        package.file_path(file),
+                    );
                 }
-                scanners::DeletedFileType::XEX(offset) => {}
-                scanners::DeletedFileType::Bink(offset) => {
-                    println!("Got a bink file at offset 0x{:X}", offset);
-                    // get the file size
-                    let file_size =
-                        LittleEndian::read_u32(&mmap[*offset as usize..*offset as usize + 0x4])
-                            as usize;
-
-                    println!("File size is 0x{:X}", file_size);
-
-                    write_file_with_raw_bytes(
-                        &deleted_files_path.join(format!("video_file_{}.bik", video_files)),
-                        &mmap[*offset as usize..*offset as usize + file_size + 0x8],
-                    )?;
-                    video_files += 1;
+
+                Ok(())
+            }
+        }
+    });
+
+    result.map_err(format_error)
+}
+
+fn open_xdbf_file(
+    partitions: &[fatx::Partition],
+    path: &str,
+    inner_path: Option<&str>,
+) -> Result<(), io::Error> {
+    let (partition, path) = resolve_partition(partitions, path)?;
+    let entry = partition.lookup(path).map_err(disk_error)?;
+
+    let mut data = vec![];
+    match inner_path {
+        Some(inner_path) => {
+            let mut package =
+                stfs::StfsPackage::open(entry.reader(partition)).map_err(format_error)?;
+            let inner_path = inner_path.trim_matches('/');
+            let file = package
+                .files()
+                .iter()
+                .find(|file| package.file_path(file).eq_ignore_ascii_case(inner_path))
+                .cloned()
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("no such file in package: {}", inner_path),
+                    )
+                })?;
+
+            package.write_file(&file, &mut data).map_err(format_error)?;
+        }
+        None => {
+            entry.reader(partition).read_to_end(&mut data)?;
+        }
+    }
+
+    print_xdbf_report(&data).map_err(format_error)
+}
+
+/// Prints every entry in an XDBF file along with its parsed record
+fn print_xdbf_report(data: &[u8]) -> Result<(), errors::FormatError> {
+    let file = xdbf::XdbfFile::parse(data)?;
+    println!(
+        "{} entries, {} free space regions, size: 0x{:X}",
+        file.entries().len(),
+        file.free_space().len(),
+        file.file_size()
+    );
+
+    for entry in file.entries() {
+        let record = match file.record(data, entry) {
+            Ok(record) => record,
+            Err(e) => {
+                println!("{:?} 0x{:X}: {}", entry.namespace, entry.id, e);
+                continue;
+            }
+        };
+
+        match record {
+            xdbf::Record::Achievement(achievement) => println!(
+                "Achievement {}: {} ({}G, {}) - {}",
+                achievement.id,
+                achievement.label,
+                achievement.gamerscore,
+                if achievement.is_unlocked() {
+                    format!("unlocked {}", format_timestamp(achievement.unlock_time))
+                } else {
+                    "locked".to_owned()
+                },
+                achievement.description
+            ),
+            xdbf::Record::Title(title) => println!(
+                "Title {:08X}: {} ({}/{} achievements, {}/{}G, last played {})",
+                title.title_id,
+                title.name,
+                title.achievements_unlocked,
+                title.achievement_count,
+                title.gamerscore_unlocked,
+                title.gamerscore_total,
+                format_timestamp(title.last_played)
+            ),
+            xdbf::Record::Setting(setting) => {
+                println!("Setting 0x{:08X}: {:?}", setting.id, setting.value)
+            }
+            xdbf::Record::String(string) => println!("String 0x{:X}: {}", entry.id, string),
+            xdbf::Record::Image(image) => {
+                println!("Image 0x{:X}: 0x{:X} bytes", entry.id, image.len())
+            }
+            xdbf::Record::Other(other) => println!(
+                "{:?} 0x{:X}: 0x{:X} bytes",
+                entry.namespace,
+                entry.id,
+                other.len()
+            ),
+        }
+    }
+
+    for free in file.free_space().iter().filter(|free| !free.runs_to_end()) {
+        println!(
+            "Free space at 0x{:X}: 0x{:X} bytes",
+            file.data_offset() + free.offset as u64,
+            free.length
+        );
+    }
+
+    Ok(())
+}
+
+/// Finds the data files of an SVOD package in the `.data` directory next to
+/// it, and reassembles the disc image that they hold if `output` is given
+fn write_svod_image(
+    partition: &fatx::Partition,
+    path: &str,
+    header: &stfs::XContentHeader,
+    descriptor: &stfs::SvodVolumeDescriptor,
+    output: Option<&Path>,
+) -> Result<(), io::Error> {
+    println!(
+        "SVOD image:    0x{:X} data blocks starting at block 0x{:X}{}",
+        descriptor.data_block_count,
+        descriptor.data_block_offset,
+        if descriptor.has_enhanced_gdf_layout() {
+            " (enhanced GDF layout)"
+        } else {
+            ""
+        }
+    );
+
+    let path = path.trim_end_matches('/');
+    let data_dir = partition
+        .read_dir(&format!("{}.data", path))
+        .map_err(disk_error)?;
+    let data_files = svod_data_files(&data_dir);
+
+    for entry in &data_files {
+        print_entry(entry);
+    }
+
+    if data_files.len() != header.data_file_count as usize {
+        println!(
+            "Expected {} data files but found {}",
+            header.data_file_count,
+            data_files.len()
+        );
+    }
+
+    let output = match output {
+        Some(output) => output,
+        None => return Ok(()),
+    };
+
+    let readers = data_files
+        .into_iter()
+        .map(|entry| entry.into_reader(partition))
+        .collect();
+    let mut image = stfs::svod::SvodReader::new(header, readers).map_err(format_error)?;
+
+    let name = path.rsplit('/').next().unwrap_or(path);
+    let iso_path = output.join(format!("{}.iso", name));
+    let mut writer = io::BufWriter::new(File::create(&iso_path)?);
+    let size = io::copy(&mut image, &mut writer)?;
+    writer.flush()?;
+
+    println!(
+        "Wrote 0x{:X} byte disc image to {}",
+        size,
+        iso_path.display()
+    );
+
+    Ok(())
+}
+
+/// The `Data0000`, `Data0001`, ... files in an SVOD package's data directory,
+/// in order
+fn svod_data_files(data_dir: &fatx::Directory) -> Vec<fatx::Entry> {
+    let mut data_files: Vec<fatx::Entry> = data_dir
+        .entries()
+        .iter()
+        .filter(|entry| {
+            !entry.is_dir() && !entry.is_deleted() && stfs::svod::is_data_file_name(entry.name())
+        })
+        .cloned()
+        .collect();
+    data_files.sort_by_key(|entry| entry.name().to_ascii_uppercase());
+
+    data_files
+}
+
+/// Read and Seek over a disc image, which is either a file in a partition or
+/// reassembled from an SVOD package's data files
+trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+type DiscImage<'p> = xdvdfs::XdvdfsVolume<Box<dyn ReadSeek + 'p>>;
+
+/// Splits a path inside of a partition at the first component that is a
+/// file, returning the path up to and including that file and the rest of the
+/// path. Returns `None` if every component is a directory.
+fn split_at_file<'s>(
+    partition: &fatx::Partition,
+    path: &'s str,
+) -> Result<Option<(&'s str, &'s str)>, io::Error> {
+    let path = path.trim_matches('/');
+    let component_ends = path
+        .match_indices('/')
+        .map(|(idx, _)| idx)
+        .chain(std::iter::once(path.len()));
+
+    for end in component_ends {
+        let entry = partition.lookup(&path[..end]).map_err(disk_error)?;
+        if !entry.is_dir() {
+            let rest = path.get(end + 1..).unwrap_or("");
+            return Ok(Some((&path[..end], rest)));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Looks up the entry at `path` along with the directory that contains it
+fn lookup_with_siblings(
+    partition: &fatx::Partition,
+    path: &str,
+) -> Result<(fatx::Entry, fatx::Directory), io::Error> {
+    let entry = partition.lookup(path).map_err(disk_error)?;
+    let parent_path = match path.rfind('/') {
+        Some(idx) => &path[..idx],
+        None => "",
+    };
+    let parent = partition.read_dir(parent_path).map_err(disk_error)?;
+
+    Ok((entry, parent))
+}
+
+/// Opens the XDVDFS disc image stored in `entry`. This is either a raw image
+/// or the header of an SVOD package, whose data directory is looked for
+/// among the entry's `siblings`.
+fn open_disc_image<'p>(
+    partition: &'p fatx::Partition,
+    entry: &fatx::Entry,
+    siblings: &[fatx::Entry],
+) -> Result<DiscImage<'p>, io::Error> {
+    let mut reader = entry.clone().into_reader(partition);
+
+    if let Ok(header) = stfs::XContentHeader::parse(&mut reader) {
+        if header.svod_volume_descriptor().is_some() {
+            let data_dir_name = format!("{}.data", entry.name());
+            let data_dir = siblings
+                .iter()
+                .find(|e| {
+                    e.is_dir() && !e.is_deleted() && e.name().eq_ignore_ascii_case(&data_dir_name)
+                })
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("SVOD data directory {} is missing", data_dir_name),
+                    )
+                })?;
+            let data_dir = fatx::Directory::parse(data_dir, partition, data_dir.name().to_owned())?;
+
+            let readers = svod_data_files(&data_dir)
+                .into_iter()
+                .map(|entry| entry.into_reader(partition))
+                .collect();
+            let image = stfs::svod::SvodReader::new(&header, readers).map_err(format_error)?;
+
+            return xdvdfs::XdvdfsVolume::open(Box::new(image) as Box<dyn ReadSeek>)
+                .map_err(format_error);
+        }
+    }
+
+    xdvdfs::XdvdfsVolume::open(Box::new(reader) as Box<dyn ReadSeek>).map_err(format_error)
+}
+
+/// Writes every file in a disc image to `output`, recreating its directory
+/// structure
+fn extract_image<R: Read + Seek>(
+    image: &mut xdvdfs::XdvdfsVolume<R>,
+    output: &Path,
+) -> Result<(), errors::FormatError> {
+    let mut pending = vec![(image.root().clone(), output.to_path_buf())];
+
+    while let Some((dir, dir_path)) = pending.pop() {
+        fs::create_dir_all(&dir_path)?;
+
+        for entry in image.read_dir(&dir)? {
+            let entry_path = match join_untrusted_path(&dir_path, entry.name()) {
+                Some(entry_path) => entry_path,
+                None => {
+                    println!("Skipping file with invalid name {}", entry.name());
+                    continue;
                 }
+            };
+            if entry.is_dir() {
+                pending.push((entry, entry_path));
+                continue;
             }
+
+            println!("{}", entry_path.display());
+
+            let mut writer = io::BufWriter::new(File::create(&entry_path)?);
+            image.write_file(&entry, &mut writer)?;
+            writer.flush()?;
+        }
+    }
+
+    Ok(())
+}
+
+fn print_stfs_metadata(header: &stfs::XContentHeader) {
+    println!("Package type:  {:?}", header.package_type);
+    println!(
+        "Content type:  {} (0x{:X})",
+        header.content_type_name().unwrap_or("Unknown"),
+        header.content_type
+    );
+    println!("Title ID:      {:08X}", header.title_id);
+    println!("Media ID:      {:08X}", header.media_id);
+    println!(
+        "Console ID:    {}",
+        header
+            .console_id
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<String>()
+    );
+    println!("Profile ID:    {:016X}", header.profile_id);
+    println!("Title name:    {}", header.title_name);
+    println!("Publisher:     {}", header.publisher_name);
+
+    let localized = [
+        ("Display name", &header.display_names),
+        ("Description", &header.descriptions),
+    ];
+    for (field, strings) in localized.iter() {
+        for (i, string) in strings.iter().enumerate() {
+            if string.is_empty() {
+                continue;
+            }
+
+            match stfs::locale_name(i) {
+                Some(locale) => println!("{} ({}): {}", field, locale, string),
+                None => println!("{} (locale {}): {}", field, i, string),
+            }
+        }
+    }
+}
+
+/// Verifies the package's hashes and reports which files are intact
+fn print_stfs_verification<R: Read + Seek>(
+    package: &mut stfs::StfsPackage<R>,
+) -> Result<(), errors::FormatError> {
+    let verification = package.verify()?;
+
+    for table in verification.hash_tables() {
+        if table.status != stfs::BlockStatus::Valid {
+            println!(
+                "Level {} hash table {}: {:?}",
+                table.level.as_u8(),
+                table.index,
+                table.status
+            );
         }
     }
 
+    let blocks = verification.blocks();
+    let valid_blocks = blocks
+        .iter()
+        .filter(|block| **block == stfs::BlockStatus::Valid)
+        .count();
+    println!(
+        "Hash verification: {} ({} of {} blocks valid)",
+        if verification.is_intact() {
+            "intact"
+        } else {
+            "damaged"
+        },
+        valid_blocks,
+        blocks.len()
+    );
+
+    if verification.is_intact() {
+        return Ok(());
+    }
+
+    let files = package.files().to_vec();
+    for file in files.iter().filter(|file| !file.is_dir()) {
+        let status = match verification.bad_file_blocks(package, file) {
+            Ok(0) => "intact".to_owned(),
+            Ok(bad_blocks) => format!(
+                "{} of {} blocks failed verification",
+                bad_blocks,
+                file.block_count()
+            ),
+            Err(e) => format!("block chain could not be followed ({})", e),
+        };
+
+        println!("  {}: {}", package.file_path(file), status);
+    }
+
     Ok(())
 }
 
+/// Writes the package's thumbnails to `dir`, prefixing their file names with
+/// `prefix`
+fn write_stfs_thumbnails(
+    header: &stfs::XContentHeader,
+    dir: &Path,
+    prefix: &str,
+) -> Result<(), io::Error> {
+    let thumbnails = [
+        ("thumbnail", &header.thumbnail),
+        ("title_thumbnail", &header.title_thumbnail),
+    ];
+
+    for (name, data) in thumbnails.iter() {
+        if data.is_empty() {
+            continue;
+        }
+
+        let path = dir.join(format!("{}{}.png", prefix, name));
+        println!("Writing {} to {}", name.replace('_', " "), path.display());
+        write_file_with_raw_bytes(&path, data)?;
+    }
+
+    Ok(())
+}
+
+/// Writes every file in the package to `output`, recreating its directory
+/// structure
+fn extract_stfs_package<R: Read + Seek>(
+    package: &mut stfs::StfsPackage<R>,
+    output: &Path,
+) -> Result<(), errors::FormatError> {
+    let files = package.files().to_vec();
+    for file in files.iter().filter(|file| !file.is_dir()) {
+        let file_path = match join_untrusted_path(output, &package.file_path(file)) {
+            Some(file_path) => file_path,
+            None => {
+                println!(
+                    "Skipping file with invalid path {}",
+                    package.file_path(file)
+                );
+                continue;
+            }
+        };
+        println!("{}", file_path.display());
+
+        if let Some(parent) = file_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut writer = io::BufWriter::new(File::create(&file_path)?);
+        package.write_file(file, &mut writer)?;
+        writer.flush()?;
+    }
+
+    Ok(())
+}
+
+/// Joins a `/`-separated path read from a package or image onto `output`.
+/// Each component is sanitized so that the result can't escape `output`, and
+/// `None` is returned if a component is `.` or `..`.
+fn join_untrusted_path(output: &Path, path: &str) -> Option<PathBuf> {
+    let mut joined = output.to_path_buf();
+
+    for component in path.split('/').filter(|c| !c.is_empty()) {
+        let component = carvers::sanitize_file_name(component);
+        if component.is_empty() || component == "." || component == ".." {
+            return None;
+        }
+
+        joined.push(component);
+    }
+
+    if joined == output {
+        return None;
+    }
+
+    Some(joined)
+}
+
+/// Writes the basefile of an XEX2 file to `path`. Errors are only reported
+/// since most retail executables are encrypted.
+fn write_xex_basefile(xex_data: &[u8], path: &Path) {
+    let result = xex::XexHeader::parse(xex_data).and_then(|header| {
+        let mut file = io::BufWriter::new(File::create(path)?);
+        xex::write_basefile(&header, xex_data, &mut file)
+    });
+
+    match result {
+        Ok(size) => println!("Wrote 0x{:X} byte basefile to {}", size, path.display()),
+        Err(e) => {
+            println!("Could not extract basefile to {}: {}", path.display(), e);
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
 fn write_file_with_raw_bytes(path: &Path, bytes: &[u8]) -> Result<(), io::Error> {
     let mut file = File::create(path)?;
-    file.write(bytes)?;
+    file.write_all(bytes)?;
 
     Ok(())
 }
 
-fn print_dir(
+fn format_timestamp(timestamp: Option<NaiveDateTime>) -> String {
+    match timestamp {
+        Some(timestamp) => timestamp.format("%Y-%m-%d %H:%M:%S").to_string(),
+        None => "-".to_owned(),
+    }
+}
+
+/// What `extract_dir` writes out
+struct ExtractOptions<'f> {
+    /// Only files matching one of these are extracted, unless it's empty
+    filters: &'f [Pattern],
+    /// Also extract the contents of disc images and SVOD packages
+    images: bool,
+    /// Also extract deleted entries, which is how the contents of a deleted
+    /// directory are recovered
+    include_deleted: bool,
+}
+
+/// Extracts the files under `dir`. `visited` holds the first cluster of every
+/// directory extracted so far so that a corrupt directory pointing back at
+/// one of its parents can't be followed forever.
+fn extract_dir(
     dir: &fatx::Directory,
     parent_path: &Path,
     partition: &fatx::Partition,
     output_dir: &Path,
-) {
-    println!("Printing dir: {}", dir.name());
+    options: &ExtractOptions,
+    visited: &mut HashSet<usize>,
+) -> Result<(), io::Error> {
     let this_dir_path = if dir.name() == "/" {
         parent_path.join("")
     } else {
@@ -203,22 +1295,76 @@ fn print_dir(
     let child_output_path = output_dir.join(&this_dir_path);
 
     for entry in dir.entries() {
+        // The clusters of deleted entries may since have been reused
+        if entry.is_deleted() && !options.include_deleted {
+            continue;
+        }
+
         if entry.is_dir() {
-            // ignore errors here
-            fs::create_dir(&child_output_path);
-
-            print_dir(
-                &fatx::Directory::parse(entry, partition, entry.name().to_owned())
-                    .expect("could not parse directory"),
-                &this_dir_path,
-                partition,
-                output_dir,
-            );
+            if !visited.insert(entry.block()) {
+                println!(
+                    "Skipping directory {} which was already extracted",
+                    this_dir_path.join(entry.name()).display()
+                );
+                continue;
+            }
+
+            let result = fatx::Directory::parse(entry, partition, entry.name().to_owned())
+                .and_then(|child| {
+                    extract_dir(
+                        &child,
+                        &this_dir_path,
+                        partition,
+                        output_dir,
+                        options,
+                        visited,
+                    )
+                });
+
+            // Deleted directories are recovered on a best-effort basis
+            match result {
+                Err(e) if entry.is_deleted() => println!(
+                    "Could not extract deleted directory {}: {}",
+                    this_dir_path.join(entry.name()).display(),
+                    e
+                ),
+                result => result?,
+            }
         } else {
-            println!("{}", this_dir_path.join(entry.name()).display());
-            entry
-                .write_to_file(&child_output_path.join(entry.name()), partition)
-                .expect("could not write output file");
+            let entry_path = this_dir_path.join(entry.name());
+            if !matches_filters(&entry_path, options.filters) {
+                continue;
+            }
+
+            println!(
+                "{}{} (created: {}, modified: {}, accessed: {})",
+                entry_path.display(),
+                if entry.is_deleted() { " (deleted)" } else { "" },
+                format_timestamp(entry.created()),
+                format_timestamp(entry.modified()),
+                format_timestamp(entry.accessed()),
+            );
+
+            fs::create_dir_all(&child_output_path)?;
+            match entry.write_to_file(&child_output_path.join(entry.name()), partition) {
+                Err(e) if entry.is_deleted() || e.kind() == io::ErrorKind::InvalidData => {
+                    println!("Could not write {}: {}", entry_path.display(), e);
+                    continue;
+                }
+                result => result?,
+            }
+
+            if options.images {
+                if let Ok(mut image) = open_disc_image(partition, entry, dir.entries()) {
+                    let contents_path =
+                        child_output_path.join(format!("{}_contents", entry.name()));
+                    if let Err(e) = extract_image(&mut image, &contents_path) {
+                        println!("Could not extract disc image contents: {}", e);
+                    }
+                }
+            }
         }
     }
+
+    Ok(())
 }
@@ -1,136 +1,445 @@
-use crate::fatx::{self, Directory, Entry, Partition};
-use byteorder::{BigEndian, ByteOrder};
-use indicatif::ProgressBar;
-use std::sync::{Arc, RwLock};
+//! Signature scanning for deleted FATX entries and known file formats.
+
+use crate::fatx::{self, AllocationBitmap, ClusterIndex, Entry, Partition};
+use std::cmp;
 use std::thread;
 
+/// Distance between the offsets that are checked for deleted entries
+const SCAN_STEP: usize = 0x10;
+
+/// Something found by `find_deleted_files`. Offsets are relative to the
+/// start of the disk.
 #[derive(Debug, Clone)]
 pub enum DeletedFileType {
-    XEX(u64),
-    STFS(u64),
     FatxEntry(Entry),
-    Bink(u64),
+    Carved(CarvedFile),
+    /// A file found inside of the clusters of a live entry, whose path is the
+    /// second field. These are only found by `ScanMode::All`.
+    Embedded(CarvedFile, String),
 }
 
+/// A file found by a `Carver`
+#[derive(Debug, Clone)]
+pub struct CarvedFile {
+    carver: &'static str,
+    offset: u64,
+    size: Option<u64>,
+    truncated: bool,
+    name: Option<String>,
+    extension: &'static str,
+}
+
+impl CarvedFile {
+    pub fn new(carver: &dyn Carver, offset: u64, size: Option<u64>) -> CarvedFile {
+        CarvedFile {
+            carver: carver.name(),
+            offset,
+            size,
+            truncated: false,
+            name: None,
+            extension: carver.extension(),
+        }
+    }
+
+    /// Suggests a file name (without an extension) for the carved file
+    pub fn with_name(mut self, name: String) -> CarvedFile {
+        self.name = Some(name);
+        self
+    }
+
+    /// Overrides the carver's extension, for formats that are used by several
+    /// kinds of files
+    pub fn with_extension(mut self, extension: &'static str) -> CarvedFile {
+        self.extension = extension;
+        self
+    }
+
+    /// Name of the carver that found this file
+    pub fn carver(&self) -> &'static str {
+        self.carver
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Length of the file, if the carver was able to work it out
+    pub fn size(&self) -> Option<u64> {
+        self.size
+    }
+
+    /// Whether the file was cut short because its length runs past the end
+    /// of the scanned area
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+
+    /// Cuts the file short at `end_offset`, if it runs past it
+    fn truncate_at(&mut self, end_offset: u64) {
+        if let Some(size) = self.size {
+            // A corrupt header can give a size that overflows
+            if self
+                .offset
+                .checked_add(size)
+                .is_none_or(|end| end > end_offset)
+            {
+                println!(
+                    "{} file at 0x{:X} is 0x{:X} bytes long but runs past the end of the partition at 0x{:X}",
+                    self.carver, self.offset, size, end_offset
+                );
+
+                self.size = Some(end_offset.saturating_sub(self.offset));
+                self.truncated = true;
+            }
+        }
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn extension(&self) -> &'static str {
+        self.extension
+    }
+
+    /// Name to write the file out as, including the extension. Falls back to
+    /// the carver name and offset if the carver didn't suggest a name.
+    pub fn file_name(&self) -> String {
+        let name = match &self.name {
+            Some(name) => name.clone(),
+            None => format!("{}_{:X}", self.carver, self.offset),
+        };
+
+        if self.extension.is_empty() {
+            name
+        } else {
+            format!("{}.{}", name, self.extension)
+        }
+    }
+}
+
+/// A signature-based scanner for a single file format
+pub trait Carver: Send + Sync {
+    /// Short name of the format, e.g. "xex"
+    fn name(&self) -> &'static str;
+
+    /// Extension used when writing carved files out
+    fn extension(&self) -> &'static str {
+        ""
+    }
+
+    /// Magic bytes that the file may start with
+    fn signatures(&self) -> &[&'static [u8]];
+
+    /// Alignment of the start of the file on disk. Must be a power of two.
+    fn alignment(&self) -> usize {
+        SCAN_STEP
+    }
+
+    /// Cheap check of the bytes following a signature hit, used to throw out
+    /// obvious false positives. `header` runs to the end of the scanned area.
+    fn validate(&self, header: &[u8]) -> bool {
+        let _ = header;
+        true
+    }
+
+    /// Parses the file at `data[offset..]` and works out its extent. `data`
+    /// is the whole disk, and files that run past the end of the scanned area
+    /// are truncated by the scanner. Returns `None` if the file turns out to be
+    /// invalid.
+    fn carve(&self, data: &[u8], offset: usize) -> Option<CarvedFile>;
+}
+
+/// The set of carvers used by `find_deleted_files`
+#[derive(Default)]
+pub struct CarverRegistry {
+    carvers: Vec<Box<dyn Carver>>,
+}
+
+impl CarverRegistry {
+    pub fn new() -> CarverRegistry {
+        CarverRegistry::default()
+    }
+
+    pub fn register(&mut self, carver: Box<dyn Carver>) {
+        self.carvers.push(carver);
+    }
+
+    pub fn carvers(&self) -> &[Box<dyn Carver>] {
+        &self.carvers
+    }
+
+    /// Smallest alignment of any registered carver (or the deleted entry
+    /// scan), which is the step the scanner has to take
+    fn scan_step(&self) -> usize {
+        self.carvers
+            .iter()
+            .map(|c| c.alignment())
+            .fold(SCAN_STEP, cmp::min)
+    }
+
+    /// Runs every carver whose signature matches at `offset`
+    fn carve_at(&self, data: &[u8], offset: usize, end_offset: usize) -> Vec<CarvedFile> {
+        let mut carved = vec![];
+        let header = &data[offset..end_offset];
+
+        for carver in &self.carvers {
+            if !offset.is_multiple_of(carver.alignment()) {
+                continue;
+            }
+
+            if !carver
+                .signatures()
+                .iter()
+                .any(|sig| header.starts_with(sig))
+            {
+                continue;
+            }
+
+            if !carver.validate(header) {
+                continue;
+            }
+
+            if let Some(mut file) = carver.carve(data, offset) {
+                file.truncate_at(end_offset as u64);
+                carved.push(file);
+            }
+        }
+
+        carved
+    }
+}
+
+/// Which parts of a partition `find_deleted_files` looks at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScanMode {
+    /// Scan every offset, including clusters that belong to live files
+    All,
+    /// Only carve clusters that the FAT marks as free. Directory clusters are
+    /// still checked for deleted entries.
+    Unallocated,
+}
+
+/// What a cluster is used for, as far as the scanner is concerned
+#[derive(Clone, Copy)]
+enum Region<'i> {
+    /// The superblock and FAT
+    Metadata,
+    Free,
+    /// Allocated in the FAT but not part of any live entry
+    Orphaned,
+    /// Belongs to the live entry at the given path
+    Directory(&'i str),
+    File(&'i str),
+}
+
+/// Scans `data[start_offset..end_offset]` for deleted directory entries and
+/// files matched by the `carvers`. Carved files found inside of the clusters
+/// of an entry in `index` are returned as `DeletedFileType::Embedded` instead
+/// of being reported as deleted.
 pub fn find_deleted_files(
     data: &[u8],
     start_offset: u64,
     end_offset: u64,
-    known_files: &[Entry],
+    index: &ClusterIndex,
     partition: &Partition,
+    carvers: &CarverRegistry,
+    mode: ScanMode,
 ) -> Vec<DeletedFileType> {
-    // let bar = ProgressBar::new(end_offset - start_offset);
-    let deleted_files = Arc::new(RwLock::new(vec![]));
     const NUM_THREADS: usize = 8;
 
-    let mut join_handles = vec![];
     let slice_size = (end_offset - start_offset) / NUM_THREADS as u64;
 
     println!("Start offset that came in was: 0x{:X}", start_offset);
-    for i in 0..NUM_THREADS {
-        let builder = thread::Builder::new();
-
-        let deleted_files = Arc::clone(&deleted_files);
-        let join_handle = unsafe {
-            builder
-                .spawn_unchecked(move || {
-                    let start_offset = start_offset + (slice_size * i as u64);
-                    let start_offset = start_offset - (start_offset % 0x10);
-                    let end_offset = start_offset + slice_size;
-
-                    let mut current_offset = start_offset as usize;
-                    let magics = vec![
-                        u32::from_be_bytes(*b"XEX2"),
-                        u32::from_be_bytes(*b"CON "),
-                        u32::from_be_bytes(*b"LIVE"),
-                        u32::from_be_bytes(*b"PIRS"),
-                        u32::from_be_bytes(*b"BIKi"),
-                    ];
-
-                    while (current_offset as u64) < end_offset {
-                        // Check for a deleted entry
-                        let filename_len = data[current_offset];
-                        let attr = data[current_offset + 1];
-
-                        if filename_len == fatx::DELETED_FILE_FLAG {
-                            match fatx::EntryAttributes::from_bits(attr) {
-                                Some(fatx::EntryAttributes::NONE)
-                                | Some(fatx::EntryAttributes::DIRECTORY) => {
-                                    let data = &data[current_offset..current_offset + 0x40];
-                                    let new_entry =
-                                        Entry::parse(partition, data, current_offset as u64)
-                                            .expect(&format!(
-                                                "failed to parse entry at 0x{:X}",
-                                                current_offset
-                                            ));
-
-                                    if let Some(new_entry) = new_entry {
-                                        println!(
-                                            "Maybe found hidden file found at 0x{:X}",
-                                            current_offset
-                                        );
-                                        let mut deleted_files = deleted_files.write().unwrap();
-                                        deleted_files.push(DeletedFileType::FatxEntry(new_entry));
-                                    }
-                                }
-                                _ => {}
-                            }
+
+    let bitmap = partition.allocation_bitmap();
+    println!(
+        "{} of {} clusters are free",
+        bitmap.free_count(),
+        bitmap.cluster_count()
+    );
+
+    let scanner = Scanner {
+        data,
+        partition,
+        carvers,
+        mode,
+        bitmap: &bitmap,
+        index,
+        scan_end_offset: end_offset,
+    };
+    let scanner = &scanner;
+
+    // Scoped threads can borrow the disk and partition directly and are all
+    // joined before the scope returns
+    thread::scope(|scope| {
+        let join_handles: Vec<_> = (0..NUM_THREADS)
+            .map(|i| {
+                let slice_start = start_offset + (slice_size * i as u64);
+                let slice_start = slice_start - (slice_start % SCAN_STEP as u64);
+                let slice_end = if i == NUM_THREADS - 1 {
+                    end_offset
+                } else {
+                    slice_start + slice_size
+                };
+
+                scope.spawn(move || scanner.scan_slice(slice_start, slice_end))
+            })
+            .collect();
+
+        join_handles
+            .into_iter()
+            .enumerate()
+            .flat_map(|(i, thread)| {
+                println!("Waiting for thread {} to join", i);
+                thread.join().expect("scanner thread panicked")
+            })
+            .collect()
+    })
+}
+
+/// State shared by the scanner threads
+struct Scanner<'a, 'i> {
+    data: &'a [u8],
+    partition: &'a Partition<'a>,
+    carvers: &'a CarverRegistry,
+    mode: ScanMode,
+    bitmap: &'a AllocationBitmap,
+    index: &'i ClusterIndex,
+    scan_end_offset: u64,
+}
+
+impl<'i> Scanner<'_, 'i> {
+    fn region(&self, offset: u64) -> Region<'i> {
+        let cluster = match self.partition.cluster_at(offset) {
+            Some(cluster) => cluster,
+            None => return Region::Metadata,
+        };
+
+        if !self.bitmap.is_allocated(cluster) {
+            return Region::Free;
+        }
+
+        match self.index.owner(cluster) {
+            Some(owner) if owner.entry.is_dir() => Region::Directory(owner.path),
+            Some(owner) => Region::File(owner.path),
+            None => Region::Orphaned,
+        }
+    }
+
+    /// Offset of the start of the cluster after the one containing `offset`
+    fn next_cluster_offset(&self, offset: u64) -> u64 {
+        let data_start = self.partition.data_start();
+        if offset < data_start {
+            return data_start;
+        }
+
+        let cluster_size = self.partition.cluster_size() as u64;
+        offset - (offset - data_start) % cluster_size + cluster_size
+    }
+
+    fn scan_slice(&self, start_offset: u64, end_offset: u64) -> Vec<DeletedFileType> {
+        let data = self.data;
+        let mut deleted_files = vec![];
+        let mut current_offset = start_offset as usize;
+        let scan_end_offset = cmp::min(self.scan_end_offset as usize, data.len());
+        let step = self.carvers.scan_step();
+
+        let mut region = self.region(start_offset);
+        let mut region_end = self.next_cluster_offset(start_offset);
+
+        while (current_offset as u64) < end_offset && current_offset + 0x40 <= data.len() {
+            if current_offset as u64 >= region_end {
+                region = self.region(current_offset as u64);
+                region_end = self.next_cluster_offset(current_offset as u64);
+            }
+
+            let (check_entries, carve) = match (self.mode, region) {
+                (_, Region::File(_)) => (false, self.mode == ScanMode::All),
+                (_, Region::Directory(_)) => (true, self.mode == ScanMode::All),
+                (ScanMode::All, _) | (_, Region::Free) => (true, true),
+                (ScanMode::Unallocated, _) => (false, false),
+            };
+
+            if !check_entries && !carve {
+                current_offset = region_end as usize;
+                continue;
+            }
+
+            // Check for a deleted entry
+            let filename_len = data[current_offset];
+            let attr = data[current_offset + 1];
+
+            if check_entries
+                && current_offset.is_multiple_of(SCAN_STEP)
+                && filename_len == fatx::DELETED_FILE_FLAG
+            {
+                match fatx::EntryAttributes::from_bits(attr) {
+                    Some(fatx::EntryAttributes::NONE) | Some(fatx::EntryAttributes::DIRECTORY) => {
+                        let data = &data[current_offset..current_offset + 0x40];
+                        let new_entry = Entry::parse(self.partition, data, current_offset as u64)
+                            .unwrap_or_else(|e| {
+                                panic!("failed to parse entry at 0x{:X}: {}", current_offset, e)
+                            });
+
+                        if let Some(new_entry) = new_entry {
+                            println!("Maybe found hidden file found at 0x{:X}", current_offset);
+                            deleted_files.push(DeletedFileType::FatxEntry(new_entry));
                         }
+                    }
+                    _ => {}
+                }
+            }
 
-                        // Check for an XEX2 header
-                        let magic =
-                            BigEndian::read_u32(&data[current_offset..current_offset + 0x4]);
-                        for m in &magics {
-                            if magic == *m {
-                                match data[current_offset + 0x5] {
-                                    0x20 | 0x2e => break,
-                                    _ => {}
-                                }
-
-                                let mut is_known = false;
-                                for entry in known_files {
-                                    if entry.offset() == current_offset as u64 {
-                                        is_known = true;
-                                        break;
-                                    }
-                                }
-
-                                if !is_known {
-                                    println!(
-                                        "Found {} magic at 0x{:X}",
-                                        String::from_utf8_lossy(&magic.to_be_bytes()),
-                                        current_offset
-                                    );
-
-                                    let mut deleted_files = deleted_files.write().unwrap();
-                                    if magic == u32::from_be_bytes(*b"XEX2") {
-                                        deleted_files
-                                            .push(DeletedFileType::XEX(current_offset as u64));
-                                    } else if magic == u32::from_be_bytes(*b"BIKi") {
-                                        deleted_files
-                                            .push(DeletedFileType::Bink(current_offset as u64));
-                                    } else {
-                                        deleted_files
-                                            .push(DeletedFileType::STFS(current_offset as u64));
-                                    }
-                                }
-                            }
+            if carve {
+                for file in self.carvers.carve_at(data, current_offset, scan_end_offset) {
+                    match region {
+                        Region::File(owner) | Region::Directory(owner) => {
+                            debug!(
+                                "Found {} file at 0x{:X} inside of {}",
+                                file.carver(),
+                                current_offset,
+                                owner
+                            );
+
+                            deleted_files.push(DeletedFileType::Embedded(file, owner.to_owned()));
                         }
+                        _ => {
+                            debug!("Found {} file at 0x{:X}", file.carver(), current_offset);
 
-                        current_offset += 0x10;
+                            deleted_files.push(DeletedFileType::Carved(file));
+                        }
                     }
-                })
-                .unwrap()
-        };
+                }
+            }
 
-        join_handles.push(join_handle);
-    }
+            current_offset += step;
+        }
 
-    for (i, thread) in join_handles.drain(..).enumerate() {
-        println!("Waiting for thread {} to join", i);
-        thread.join();
+        deleted_files
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::carvers::XexCarver;
 
-    let entries = deleted_files.read().unwrap();
-    entries.clone()
+    #[test]
+    fn truncate_at_cuts_oversized_files() {
+        let mut file = CarvedFile::new(&XexCarver, 0x100, Some(0x2000));
+        file.truncate_at(0x1000);
+        assert_eq!(file.size(), Some(0xF00));
+        assert!(file.is_truncated());
+
+        let mut file = CarvedFile::new(&XexCarver, 0x100, Some(u64::MAX));
+        file.truncate_at(0x1000);
+        assert_eq!(file.size(), Some(0xF00));
+        assert!(file.is_truncated());
+
+        let mut file = CarvedFile::new(&XexCarver, 0x100, Some(0x100));
+        file.truncate_at(0x1000);
+        assert_eq!(file.size(), Some(0x100));
+        assert!(!file.is_truncated());
+    }
 }
@@ -0,0 +1,439 @@
+//! The XContent header shared by CON, LIVE and PIRS packages.
+
+use crate::errors::FormatError;
+use byteorder::{BigEndian, LittleEndian, ReadBytesExt};
+use std::cmp;
+use std::io::{Read, Seek, SeekFrom};
+
+const HEADER_SIZE_OFFSET: u64 = 0x340;
+const VOLUME_DESCRIPTOR_OFFSET: u64 = 0x379;
+const DATA_FILE_COUNT_OFFSET: u64 = 0x39D;
+const DISPLAY_NAME_OFFSET: u64 = 0x411;
+const DESCRIPTION_OFFSET: u64 = 0xD11;
+const PUBLISHER_NAME_OFFSET: u64 = 0x1611;
+const TITLE_NAME_OFFSET: u64 = 0x1691;
+const THUMBNAIL_SIZE_OFFSET: u64 = 0x1712;
+const THUMBNAIL_OFFSET: u64 = 0x171A;
+const TITLE_THUMBNAIL_OFFSET: u64 = 0x571A;
+/// Space reserved for each of the thumbnails
+const MAX_THUMBNAIL_SIZE: u32 = 0x4000;
+/// Size of a single locale's string
+const LOCALIZED_STRING_SIZE: usize = 0x80;
+/// Number of locales that display names and descriptions are stored for
+const LOCALE_COUNT: usize = 18;
+const VOLUME_DESCRIPTOR_SIZE: u8 = 0x24;
+/// Most blocks an STFS package can hold, which is what three levels of hash
+/// tables can address
+const MAX_STFS_BLOCK_COUNT: u32 = 0x4A_F768;
+/// SVOD feature flag for images that start right at the XDVDFS volume
+/// descriptor instead of at the start of the game partition
+const SVOD_ENHANCED_GDF_LAYOUT: u8 = 0x40;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PackageType {
+    /// Signed by a console
+    Con,
+    /// Signed by Microsoft and downloaded from Xbox Live
+    Live,
+    /// Signed by Microsoft and distributed offline
+    Pirs,
+}
+
+/// Names of the locales in the order that their strings are stored in
+const LOCALES: [&str; 12] = [
+    "English",
+    "Japanese",
+    "German",
+    "French",
+    "Spanish",
+    "Italian",
+    "Korean",
+    "Chinese (Traditional)",
+    "Portuguese",
+    "Chinese (Simplified)",
+    "Polish",
+    "Russian",
+];
+
+/// Name of the locale at `index` in `display_names` or `descriptions`
+pub fn locale_name(index: usize) -> Option<&'static str> {
+    LOCALES.get(index).copied()
+}
+
+impl PackageType {
+    pub fn from_magic(magic: &[u8]) -> Option<PackageType> {
+        match magic {
+            b"CON " => Some(PackageType::Con),
+            b"LIVE" => Some(PackageType::Live),
+            b"PIRS" => Some(PackageType::Pirs),
+            _ => None,
+        }
+    }
+}
+
+/// Describes the layout of an STFS filesystem
+#[derive(Debug, Clone)]
+pub struct StfsVolumeDescriptor {
+    pub block_separation: u8,
+    pub file_table_block_count: u16,
+    pub file_table_block_num: u32,
+    pub top_hash_table_hash: [u8; 0x14],
+    pub allocated_block_count: u32,
+    pub unallocated_block_count: u32,
+}
+
+/// Describes the layout of an SVOD filesystem, which stores a disc image in a
+/// directory of data files next to the package
+#[derive(Debug, Clone)]
+pub struct SvodVolumeDescriptor {
+    pub block_cache_element_count: u8,
+    pub worker_thread_processor: u8,
+    pub worker_thread_priority: u8,
+    pub root_hash: [u8; 0x14],
+    pub features: u8,
+    pub data_block_count: u32,
+    pub data_block_offset: u32,
+}
+
+impl SvodVolumeDescriptor {
+    pub fn has_enhanced_gdf_layout(&self) -> bool {
+        self.features & SVOD_ENHANCED_GDF_LAYOUT != 0
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum VolumeDescriptor {
+    Stfs(StfsVolumeDescriptor),
+    Svod(SvodVolumeDescriptor),
+}
+
+#[derive(Debug, Clone)]
+pub struct XContentHeader {
+    pub package_type: PackageType,
+    pub header_size: u32,
+    pub content_type: u32,
+    pub metadata_version: u32,
+    pub content_size: u64,
+    pub media_id: u32,
+    pub version: u32,
+    pub base_version: u32,
+    pub title_id: u32,
+    pub platform: u8,
+    pub executable_type: u8,
+    pub disc_number: u8,
+    pub disc_in_set: u8,
+    pub savegame_id: u32,
+    pub console_id: [u8; 5],
+    pub profile_id: u64,
+    pub volume_descriptor: VolumeDescriptor,
+    pub data_file_count: u32,
+    pub data_file_combined_size: u64,
+    /// Display names for every locale, some of which may be empty
+    pub display_names: Vec<String>,
+    /// Descriptions for every locale, some of which may be empty
+    pub descriptions: Vec<String>,
+    pub publisher_name: String,
+    pub title_name: String,
+    /// PNG image shown for the package
+    pub thumbnail: Vec<u8>,
+    /// PNG image of the title that the package belongs to
+    pub title_thumbnail: Vec<u8>,
+}
+
+impl XContentHeader {
+    /// Reads the header from the start of `reader`
+    pub fn parse<R: Read + Seek>(reader: &mut R) -> Result<XContentHeader, FormatError> {
+        reader.seek(SeekFrom::Start(0))?;
+
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        let package_type =
+            PackageType::from_magic(&magic).ok_or_else(|| FormatError::InvalidMagic {
+                format: "STFS",
+                magic: u32::from_be_bytes(magic),
+            })?;
+
+        reader.seek(SeekFrom::Start(HEADER_SIZE_OFFSET))?;
+        let header_size = reader.read_u32::<BigEndian>()?;
+        let content_type = reader.read_u32::<BigEndian>()?;
+        let metadata_version = reader.read_u32::<BigEndian>()?;
+        let content_size = reader.read_u64::<BigEndian>()?;
+        let media_id = reader.read_u32::<BigEndian>()?;
+        let version = reader.read_u32::<BigEndian>()?;
+        let base_version = reader.read_u32::<BigEndian>()?;
+        let title_id = reader.read_u32::<BigEndian>()?;
+        let platform = reader.read_u8()?;
+        let executable_type = reader.read_u8()?;
+        let disc_number = reader.read_u8()?;
+        let disc_in_set = reader.read_u8()?;
+        let savegame_id = reader.read_u32::<BigEndian>()?;
+        let mut console_id = [0u8; 5];
+        reader.read_exact(&mut console_id)?;
+        let profile_id = reader.read_u64::<BigEndian>()?;
+
+        reader.seek(SeekFrom::Start(DATA_FILE_COUNT_OFFSET))?;
+        let data_file_count = reader.read_u32::<BigEndian>()?;
+        let data_file_combined_size = reader.read_u64::<BigEndian>()?;
+        let descriptor_type = reader.read_u32::<BigEndian>()?;
+
+        reader.seek(SeekFrom::Start(VOLUME_DESCRIPTOR_OFFSET))?;
+        let volume_descriptor = match descriptor_type {
+            0 => VolumeDescriptor::Stfs(read_stfs_volume_descriptor(reader)?),
+            1 => VolumeDescriptor::Svod(read_svod_volume_descriptor(reader)?),
+            _ => {
+                return Err(FormatError::InvalidHeader {
+                    format: "STFS",
+                    reason: "unknown volume descriptor type",
+                })
+            }
+        };
+
+        reader.seek(SeekFrom::Start(DISPLAY_NAME_OFFSET))?;
+        let display_names = read_localized_strings(reader)?;
+        reader.seek(SeekFrom::Start(DESCRIPTION_OFFSET))?;
+        let descriptions = read_localized_strings(reader)?;
+        reader.seek(SeekFrom::Start(PUBLISHER_NAME_OFFSET))?;
+        let publisher_name = read_utf16_string(reader, LOCALIZED_STRING_SIZE)?;
+        reader.seek(SeekFrom::Start(TITLE_NAME_OFFSET))?;
+        let title_name = read_utf16_string(reader, LOCALIZED_STRING_SIZE)?;
+
+        reader.seek(SeekFrom::Start(THUMBNAIL_SIZE_OFFSET))?;
+        let thumbnail_size = reader.read_u32::<BigEndian>()?;
+        let title_thumbnail_size = reader.read_u32::<BigEndian>()?;
+        let thumbnail = read_thumbnail(reader, THUMBNAIL_OFFSET, thumbnail_size)?;
+        let title_thumbnail = read_thumbnail(reader, TITLE_THUMBNAIL_OFFSET, title_thumbnail_size)?;
+
+        Ok(XContentHeader {
+            package_type,
+            header_size,
+            content_type,
+            metadata_version,
+            content_size,
+            media_id,
+            version,
+            base_version,
+            title_id,
+            platform,
+            executable_type,
+            disc_number,
+            disc_in_set,
+            savegame_id,
+            console_id,
+            profile_id,
+            volume_descriptor,
+            data_file_count,
+            data_file_combined_size,
+            display_names,
+            descriptions,
+            publisher_name,
+            title_name,
+            thumbnail,
+            title_thumbnail,
+        })
+    }
+
+    /// The English display name, or the first one that isn't empty
+    pub fn display_name(&self) -> &str {
+        self.display_names
+            .iter()
+            .find(|name| !name.is_empty())
+            .map(String::as_str)
+            .unwrap_or("")
+    }
+
+    /// Human-readable name of the content type
+    pub fn content_type_name(&self) -> Option<&'static str> {
+        let name = match self.content_type {
+            0x1 => "Saved Game",
+            0x2 => "Marketplace Content",
+            0x3 => "Publisher",
+            0x1000 => "Xbox 360 Title",
+            0x2000 => "IPTV Pause Buffer",
+            0x4000 => "Installed Game",
+            0x5000 => "Xbox Original Game",
+            0x7000 => "Games on Demand",
+            0x9000 => "Avatar Item",
+            0x10000 => "Profile",
+            0x20000 => "Gamer Picture",
+            0x30000 => "Theme",
+            0x40000 => "Cache File",
+            0x50000 => "Storage Download",
+            0x60000 => "Xbox Saved Game",
+            0x70000 => "Xbox Download",
+            0x80000 => "Game Demo",
+            0x90000 => "Video",
+            0xA0000 => "Game Title",
+            0xB0000 => "Installer",
+            0xC0000 => "Game Trailer",
+            0xD0000 => "Arcade Title",
+            0xE0000 => "XNA",
+            0xF0000 => "License Store",
+            0x100000 => "Movie",
+            0x200000 => "TV",
+            0x300000 => "Music Video",
+            0x400000 => "Game Video",
+            0x500000 => "Podcast Video",
+            0x600000 => "Viral Video",
+            0x2000000 => "Community Game",
+            _ => return None,
+        };
+
+        Some(name)
+    }
+
+    pub fn stfs_volume_descriptor(&self) -> Option<&StfsVolumeDescriptor> {
+        match &self.volume_descriptor {
+            VolumeDescriptor::Stfs(descriptor) => Some(descriptor),
+            VolumeDescriptor::Svod(_) => None,
+        }
+    }
+
+    pub fn svod_volume_descriptor(&self) -> Option<&SvodVolumeDescriptor> {
+        match &self.volume_descriptor {
+            VolumeDescriptor::Svod(descriptor) => Some(descriptor),
+            VolumeDescriptor::Stfs(_) => None,
+        }
+    }
+}
+
+fn read_stfs_volume_descriptor<R: Read>(
+    reader: &mut R,
+) -> Result<StfsVolumeDescriptor, FormatError> {
+    read_volume_descriptor_size(reader)?;
+
+    let _reserved = reader.read_u8()?;
+    let block_separation = reader.read_u8()?;
+    // Unlike the rest of the header the file table location is little-endian
+    let file_table_block_count = reader.read_u16::<LittleEndian>()?;
+    let file_table_block_num = reader.read_u24::<LittleEndian>()?;
+    let mut top_hash_table_hash = [0u8; 0x14];
+    reader.read_exact(&mut top_hash_table_hash)?;
+    let allocated_block_count = reader.read_u32::<BigEndian>()?;
+    let unallocated_block_count = reader.read_u32::<BigEndian>()?;
+
+    if allocated_block_count > MAX_STFS_BLOCK_COUNT {
+        return Err(FormatError::InvalidHeader {
+            format: "STFS",
+            reason: "package has more blocks than it can address",
+        });
+    }
+
+    Ok(StfsVolumeDescriptor {
+        block_separation,
+        file_table_block_count,
+        file_table_block_num,
+        top_hash_table_hash,
+        allocated_block_count,
+        unallocated_block_count,
+    })
+}
+
+fn read_localized_strings<R: Read>(reader: &mut R) -> Result<Vec<String>, FormatError> {
+    (0..LOCALE_COUNT)
+        .map(|_| read_utf16_string(reader, LOCALIZED_STRING_SIZE))
+        .collect()
+}
+
+/// Reads a thumbnail, ignoring any bytes past the space reserved for it
+fn read_thumbnail<R: Read + Seek>(
+    reader: &mut R,
+    offset: u64,
+    size: u32,
+) -> Result<Vec<u8>, FormatError> {
+    let mut thumbnail = vec![0u8; cmp::min(size, MAX_THUMBNAIL_SIZE) as usize];
+    reader.seek(SeekFrom::Start(offset))?;
+    reader.read_exact(&mut thumbnail)?;
+
+    Ok(thumbnail)
+}
+
+fn read_svod_volume_descriptor<R: Read>(
+    reader: &mut R,
+) -> Result<SvodVolumeDescriptor, FormatError> {
+    read_volume_descriptor_size(reader)?;
+
+    let block_cache_element_count = reader.read_u8()?;
+    let worker_thread_processor = reader.read_u8()?;
+    let worker_thread_priority = reader.read_u8()?;
+    let mut root_hash = [0u8; 0x14];
+    reader.read_exact(&mut root_hash)?;
+    let features = reader.read_u8()?;
+    let data_block_count = reader.read_u24::<LittleEndian>()?;
+    let data_block_offset = reader.read_u24::<LittleEndian>()?;
+
+    Ok(SvodVolumeDescriptor {
+        block_cache_element_count,
+        worker_thread_processor,
+        worker_thread_priority,
+        root_hash,
+        features,
+        data_block_count,
+        data_block_offset,
+    })
+}
+
+fn read_volume_descriptor_size<R: Read>(reader: &mut R) -> Result<(), FormatError> {
+    if reader.read_u8()? != VOLUME_DESCRIPTOR_SIZE {
+        return Err(FormatError::InvalidHeader {
+            format: "STFS",
+            reason: "volume descriptor has the wrong size",
+        });
+    }
+
+    Ok(())
+}
+
+/// Reads a null-terminated UTF-16BE string from a field that is `size` bytes
+/// long
+pub(crate) fn read_utf16_string<R: Read>(
+    reader: &mut R,
+    size: usize,
+) -> Result<String, FormatError> {
+    let mut data = vec![0u8; size];
+    reader.read_exact(&mut data)?;
+
+    let chars: Vec<u16> = data
+        .chunks_exact(2)
+        .map(|c| u16::from_be_bytes([c[0], c[1]]))
+        .take_while(|c| *c != 0)
+        .collect();
+
+    Ok(String::from_utf16_lossy(&chars))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn stfs_volume_descriptor(allocated_block_count: u32) -> Vec<u8> {
+        let mut data = vec![VOLUME_DESCRIPTOR_SIZE, 0, 1];
+        // File table block count and block number
+        data.extend(&[1, 0, 0, 0, 0]);
+        data.extend(&[0u8; 0x14]);
+        data.extend(&allocated_block_count.to_be_bytes());
+        data.extend(&0u32.to_be_bytes());
+        data
+    }
+
+    #[test]
+    fn stfs_volume_descriptor_block_count_is_bounded() {
+        let descriptor =
+            read_stfs_volume_descriptor(&mut Cursor::new(stfs_volume_descriptor(0x100))).unwrap();
+        assert_eq!(descriptor.allocated_block_count, 0x100);
+        assert_eq!(descriptor.block_separation, 1);
+
+        assert!(
+            read_stfs_volume_descriptor(&mut Cursor::new(stfs_volume_descriptor(
+                MAX_STFS_BLOCK_COUNT
+            )))
+            .is_ok()
+        );
+        assert!(
+            read_stfs_volume_descriptor(&mut Cursor::new(stfs_volume_descriptor(
+                MAX_STFS_BLOCK_COUNT + 1
+            )))
+            .is_err()
+        );
+    }
+}
@@ -0,0 +1,653 @@
+//! Parsing of STFS (Secure Transacted File System) packages: the CON, LIVE
+//! and PIRS containers used for saves, profiles, DLC and title updates. The
+//! `svod` module handles packages that keep their data in separate files
+//! instead, such as Games on Demand.
+//!
+//! An STFS package is made up of the XContent header followed by 0x1000-byte
+//! blocks. Data blocks are interleaved with hash tables, each of which covers
+//! 0xAA blocks (or 0xAA hash tables at the higher levels). "Male" packages
+//! keep two copies of every hash table so that they can be updated
+//! transactionally, while "female" packages only have one.
+
+use crate::errors::FormatError;
+use crate::fatx;
+use byteorder::{BigEndian, LittleEndian, ReadBytesExt};
+use chrono::NaiveDateTime;
+use std::cmp;
+use std::collections::HashSet;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+mod header;
+pub mod svod;
+mod verify;
+
+pub use self::header::{
+    locale_name, PackageType, StfsVolumeDescriptor, SvodVolumeDescriptor, VolumeDescriptor,
+    XContentHeader,
+};
+pub use self::verify::{BlockStatus, HashTableStatus, Verification};
+
+pub const BLOCK_SIZE: u64 = 0x1000;
+/// Number of entries in a single hash table
+const HASHES_PER_TABLE: u32 = 0xAA;
+/// Number of data blocks covered by a level 1 hash table
+const LEVEL1_BLOCK_SPAN: u32 = HASHES_PER_TABLE * HASHES_PER_TABLE;
+const HASH_ENTRY_SIZE: u64 = 0x18;
+const FILE_ENTRY_SIZE: usize = 0x40;
+const FILE_ENTRIES_PER_BLOCK: u32 = (BLOCK_SIZE as usize / FILE_ENTRY_SIZE) as u32;
+/// `next_block` value that terminates a block chain
+pub const END_OF_CHAIN: u32 = 0xFF_FFFF;
+/// Path indicator of entries in the root directory
+const ROOT_DIRECTORY: u16 = 0xFFFF;
+/// Bit in a hash entry's status that selects the second copy of the hash
+/// table it points to
+const ACTIVE_TABLE_FLAG: u8 = 0x40;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PackageSex {
+    /// One copy of each hash table
+    Female,
+    /// Two copies of each hash table
+    Male,
+}
+
+impl PackageSex {
+    fn from_block_separation(block_separation: u8) -> PackageSex {
+        if block_separation & 1 == 1 {
+            PackageSex::Female
+        } else {
+            PackageSex::Male
+        }
+    }
+
+    /// Number of hash table copies as a power of two
+    fn shift(self) -> u32 {
+        match self {
+            PackageSex::Female => 0,
+            PackageSex::Male => 1,
+        }
+    }
+
+    /// Distance in blocks between consecutive level 0 and level 1 hash tables
+    fn block_step(self) -> [u64; 2] {
+        match self {
+            PackageSex::Female => [0xAB, 0x718F],
+            PackageSex::Male => [0xAC, 0x723A],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum HashLevel {
+    Zero,
+    One,
+    Two,
+}
+
+impl HashLevel {
+    pub fn as_u8(self) -> u8 {
+        match self {
+            HashLevel::Zero => 0,
+            HashLevel::One => 1,
+            HashLevel::Two => 2,
+        }
+    }
+
+    /// The level of the top hash table needed to cover `block_count` blocks
+    fn for_block_count(block_count: u32) -> HashLevel {
+        if block_count <= HASHES_PER_TABLE {
+            HashLevel::Zero
+        } else if block_count <= LEVEL1_BLOCK_SPAN {
+            HashLevel::One
+        } else {
+            HashLevel::Two
+        }
+    }
+}
+
+/// An entry in a hash table. At level 0 this describes a data block, and at
+/// higher levels it describes a lower level hash table.
+#[derive(Debug, Clone)]
+pub struct HashEntry {
+    pub hash: [u8; 0x14],
+    pub status: u8,
+    pub next_block: u32,
+}
+
+impl HashEntry {
+    fn parse<R: Read>(reader: &mut R) -> Result<HashEntry, FormatError> {
+        let mut hash = [0u8; 0x14];
+        reader.read_exact(&mut hash)?;
+
+        Ok(HashEntry {
+            hash,
+            status: reader.read_u8()?,
+            next_block: reader.read_u24::<BigEndian>()?,
+        })
+    }
+}
+
+/// An entry in the package's file table
+#[derive(Debug, Clone)]
+pub struct StfsFileEntry {
+    index: u32,
+    name: String,
+    is_dir: bool,
+    consecutive_blocks: bool,
+    block_count: u32,
+    starting_block: u32,
+    path_indicator: u16,
+    size: u32,
+    created: Option<NaiveDateTime>,
+    accessed: Option<NaiveDateTime>,
+}
+
+impl StfsFileEntry {
+    fn parse(data: &[u8], index: u32) -> Result<Option<StfsFileEntry>, FormatError> {
+        let flags = data[0x28];
+        let name_len = (flags & 0x3F) as usize;
+        if name_len == 0 || name_len > 0x28 {
+            return Ok(None);
+        }
+
+        let mut cursor = io::Cursor::new(&data[0x29..]);
+        let block_count = cursor.read_u24::<LittleEndian>()?;
+        let _block_count_copy = cursor.read_u24::<LittleEndian>()?;
+        let starting_block = cursor.read_u24::<LittleEndian>()?;
+        let path_indicator = cursor.read_u16::<BigEndian>()?;
+        let size = cursor.read_u32::<BigEndian>()?;
+        let created = cursor.read_u32::<BigEndian>()?;
+        let accessed = cursor.read_u32::<BigEndian>()?;
+
+        Ok(Some(StfsFileEntry {
+            index,
+            name: String::from_utf8_lossy(&data[..name_len]).into_owned(),
+            is_dir: flags & 0x80 != 0,
+            consecutive_blocks: flags & 0x40 != 0,
+            block_count,
+            starting_block,
+            path_indicator,
+            size,
+            created: fatx::decode_timestamp(created, 1980),
+            accessed: fatx::decode_timestamp(accessed, 1980),
+        }))
+    }
+
+    /// Index of the entry in the file table, which is what child entries
+    /// refer to in their path indicator
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.is_dir
+    }
+
+    /// Whether the file's blocks are numbered consecutively, in which case the
+    /// hash table doesn't have to be consulted to follow the chain
+    pub fn has_consecutive_blocks(&self) -> bool {
+        self.consecutive_blocks
+    }
+
+    pub fn block_count(&self) -> u32 {
+        self.block_count
+    }
+
+    pub fn starting_block(&self) -> u32 {
+        self.starting_block
+    }
+
+    /// Index of the parent directory's entry, or `None` for the root
+    pub fn parent_index(&self) -> Option<u32> {
+        if self.path_indicator == ROOT_DIRECTORY {
+            None
+        } else {
+            Some(self.path_indicator as u32)
+        }
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    pub fn created(&self) -> Option<NaiveDateTime> {
+        self.created
+    }
+
+    pub fn accessed(&self) -> Option<NaiveDateTime> {
+        self.accessed
+    }
+}
+
+/// Where blocks and hash tables live in a package. This only depends on the
+/// header, so it can be worked out without reading the rest of the package.
+#[derive(Debug, Clone, Copy)]
+pub struct StfsLayout {
+    sex: PackageSex,
+    first_hash_table_offset: u64,
+    top_level: HashLevel,
+    allocated_block_count: u32,
+}
+
+impl StfsLayout {
+    /// Returns `None` for packages that don't use an STFS volume descriptor
+    pub fn from_header(header: &XContentHeader) -> Option<StfsLayout> {
+        let volume = header.stfs_volume_descriptor()?;
+
+        Some(StfsLayout {
+            sex: PackageSex::from_block_separation(volume.block_separation),
+            first_hash_table_offset: header_end(header),
+            top_level: HashLevel::for_block_count(volume.allocated_block_count),
+            allocated_block_count: volume.allocated_block_count,
+        })
+    }
+
+    pub fn sex(&self) -> PackageSex {
+        self.sex
+    }
+
+    pub fn top_level(&self) -> HashLevel {
+        self.top_level
+    }
+
+    /// Converts a data block number into an offset from the start of the
+    /// package, skipping over the hash tables that precede it
+    pub fn block_to_offset(&self, block: u32) -> u64 {
+        self.backing_data_block(block) * BLOCK_SIZE + self.first_hash_table_offset
+    }
+
+    /// Offset of the first copy of the hash table at `level` covering `block`
+    fn hash_table_offset(&self, block: u32, level: HashLevel) -> u64 {
+        self.backing_hash_block(block, level) * BLOCK_SIZE + self.first_hash_table_offset
+    }
+
+    /// Size of the package on disk, which runs to the end of the last
+    /// allocated block
+    pub fn package_size(&self) -> u64 {
+        match self.allocated_block_count {
+            // Only the header and the top hash table
+            0 => self.first_hash_table_offset + (BLOCK_SIZE << self.sex.shift()),
+            count => self.block_to_offset(count - 1) + BLOCK_SIZE,
+        }
+    }
+
+    /// Block number (counted from the first hash table) of data block
+    /// `block`. Worked out in 64 bits so that garbage block numbers can't
+    /// overflow.
+    fn backing_data_block(&self, block: u32) -> u64 {
+        let block = block as u64;
+        let hashes_per_table = HASHES_PER_TABLE as u64;
+        let level1_block_span = LEVEL1_BLOCK_SPAN as u64;
+        let shift = self.sex.shift();
+
+        let backing = (((block + hashes_per_table) / hashes_per_table) << shift) + block;
+        if block < hashes_per_table {
+            return backing;
+        }
+
+        let backing = backing + (((block + level1_block_span) / level1_block_span) << shift);
+        if block < level1_block_span {
+            return backing;
+        }
+
+        backing + (1 << shift)
+    }
+
+    /// Block number (counted from the first hash table) of the hash table at
+    /// `level` that covers `block`
+    fn backing_hash_block(&self, block: u32, level: HashLevel) -> u64 {
+        let block = block as u64;
+        let hashes_per_table = HASHES_PER_TABLE as u64;
+        let level1_block_span = LEVEL1_BLOCK_SPAN as u64;
+        let shift = self.sex.shift();
+        let step = self.sex.block_step();
+
+        match level {
+            HashLevel::Zero => {
+                if block < hashes_per_table {
+                    return 0;
+                }
+
+                let num = (block / hashes_per_table) * step[0]
+                    + (((block / level1_block_span) + 1) << shift);
+                if block / level1_block_span == 0 {
+                    num
+                } else {
+                    num + (1 << shift)
+                }
+            }
+            HashLevel::One => {
+                if block < level1_block_span {
+                    step[0]
+                } else {
+                    (1 << shift) + (block / level1_block_span) * step[1]
+                }
+            }
+            HashLevel::Two => step[1],
+        }
+    }
+}
+
+/// Offset of the first block after the header
+fn header_end(header: &XContentHeader) -> u64 {
+    (header.header_size as u64 + 0xFFF) & !0xFFF
+}
+
+/// Size of a package on disk as described by its header. SVOD packages only
+/// consist of the header, with their data living in separate files.
+pub fn package_size(header: &XContentHeader) -> u64 {
+    match StfsLayout::from_header(header) {
+        Some(layout) => layout.package_size(),
+        None => header_end(header),
+    }
+}
+
+/// An STFS package read from `R`, which can be a file inside of a FATX
+/// partition (see `fatx::EntryReader`) or a carved region of the disk
+pub struct StfsPackage<R> {
+    reader: R,
+    header: XContentHeader,
+    volume: StfsVolumeDescriptor,
+    layout: StfsLayout,
+    top_table: Vec<HashEntry>,
+    files: Vec<StfsFileEntry>,
+}
+
+impl<R: Read + Seek> StfsPackage<R> {
+    pub fn open(mut reader: R) -> Result<StfsPackage<R>, FormatError> {
+        let header = XContentHeader::parse(&mut reader)?;
+        let (volume, layout) = match (&header.volume_descriptor, StfsLayout::from_header(&header)) {
+            (VolumeDescriptor::Stfs(volume), Some(layout)) => (volume.clone(), layout),
+            _ => {
+                return Err(FormatError::Unsupported {
+                    format: "STFS",
+                    reason: "package uses an SVOD volume descriptor, see the svod module",
+                })
+            }
+        };
+
+        let mut package = StfsPackage {
+            reader,
+            header,
+            volume,
+            layout,
+            top_table: vec![],
+            files: vec![],
+        };
+
+        package.top_table = package.read_top_table()?;
+        package.files = package.read_file_table()?;
+
+        Ok(package)
+    }
+
+    pub fn header(&self) -> &XContentHeader {
+        &self.header
+    }
+
+    pub fn volume_descriptor(&self) -> &StfsVolumeDescriptor {
+        &self.volume
+    }
+
+    pub fn layout(&self) -> &StfsLayout {
+        &self.layout
+    }
+
+    pub fn files(&self) -> &[StfsFileEntry] {
+        &self.files
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// Full path of the entry inside of the package, built by following path
+    /// indicators up to the root
+    pub fn file_path(&self, entry: &StfsFileEntry) -> String {
+        let mut components = vec![entry.name()];
+        let mut seen = HashSet::new();
+        let mut parent = entry.parent_index();
+
+        while let Some(index) = parent {
+            if !seen.insert(index) {
+                break;
+            }
+
+            match self.files.iter().find(|e| e.index() == index) {
+                Some(parent_entry) => {
+                    components.push(parent_entry.name());
+                    parent = parent_entry.parent_index();
+                }
+                None => break,
+            }
+        }
+
+        components.reverse();
+        components.join("/")
+    }
+
+    /// Reads the level 0 hash entry for a data block
+    pub fn hash_entry(&mut self, block: u32) -> Result<HashEntry, FormatError> {
+        let table_offset = self.hash_table_offset(block, HashLevel::Zero)?;
+        self.read_hash_entry(table_offset + (block % HASHES_PER_TABLE) as u64 * HASH_ENTRY_SIZE)
+    }
+
+    /// Data blocks that make up a file, in order
+    pub fn file_blocks(&mut self, entry: &StfsFileEntry) -> Result<Vec<u32>, FormatError> {
+        let mut blocks = Vec::with_capacity(entry.block_count() as usize);
+        let mut block = entry.starting_block();
+
+        for i in 0..entry.block_count() {
+            if block >= self.volume.allocated_block_count {
+                return Err(FormatError::InvalidHeader {
+                    format: "STFS",
+                    reason: "block chain points outside of the package",
+                });
+            }
+
+            blocks.push(block);
+
+            if i + 1 < entry.block_count() {
+                block = if entry.has_consecutive_blocks() {
+                    block + 1
+                } else {
+                    self.hash_entry(block)?.next_block
+                };
+            }
+        }
+
+        Ok(blocks)
+    }
+
+    /// Writes the contents of a file in the package to `writer`
+    pub fn write_file<W: Write>(
+        &mut self,
+        entry: &StfsFileEntry,
+        writer: &mut W,
+    ) -> Result<u64, FormatError> {
+        let mut remaining = entry.size() as u64;
+        let mut buffer = vec![0u8; BLOCK_SIZE as usize];
+
+        for block in self.file_blocks(entry)? {
+            if remaining == 0 {
+                break;
+            }
+
+            let len = cmp::min(remaining, BLOCK_SIZE) as usize;
+            self.read_block(block, &mut buffer[..len])?;
+            writer.write_all(&buffer[..len])?;
+            remaining -= len as u64;
+        }
+
+        Ok(entry.size() as u64 - remaining)
+    }
+
+    fn read_block(&mut self, block: u32, buffer: &mut [u8]) -> Result<(), FormatError> {
+        self.reader
+            .seek(SeekFrom::Start(self.layout.block_to_offset(block)))?;
+        self.reader.read_exact(buffer)?;
+
+        Ok(())
+    }
+
+    /// Offset of the active copy of the hash table at `level` covering `block`
+    fn hash_table_offset(&mut self, block: u32, level: HashLevel) -> Result<u64, FormatError> {
+        let base = self.layout.hash_table_offset(block, level);
+        if self.layout.sex == PackageSex::Female {
+            return Ok(base);
+        }
+
+        // The entry in the parent table says which of the two copies is
+        // active. The top table's copy is selected by the volume descriptor.
+        let active_flag = if level == self.layout.top_level {
+            (self.volume.block_separation & 2) << 5
+        } else {
+            self.parent_hash_entry(block, level)?.status
+        };
+
+        if active_flag & ACTIVE_TABLE_FLAG != 0 {
+            Ok(base + BLOCK_SIZE)
+        } else {
+            Ok(base)
+        }
+    }
+
+    /// The entry in the level above `level` that describes the hash table
+    /// covering `block`
+    fn parent_hash_entry(
+        &mut self,
+        block: u32,
+        level: HashLevel,
+    ) -> Result<HashEntry, FormatError> {
+        let (parent_level, span) = match level {
+            HashLevel::Zero => (HashLevel::One, HASHES_PER_TABLE),
+            HashLevel::One => (HashLevel::Two, LEVEL1_BLOCK_SPAN),
+            HashLevel::Two => unreachable!("level two tables have no parent"),
+        };
+
+        let index = (block / span) % HASHES_PER_TABLE;
+        if parent_level == self.layout.top_level {
+            return self
+                .top_table
+                .get(index as usize)
+                .cloned()
+                .ok_or(FormatError::InvalidHeader {
+                    format: "STFS",
+                    reason: "block is not covered by the top hash table",
+                });
+        }
+
+        let parent_offset = self.hash_table_offset(block, parent_level)?;
+        self.read_hash_entry(parent_offset + index as u64 * HASH_ENTRY_SIZE)
+    }
+
+    fn read_hash_entry(&mut self, offset: u64) -> Result<HashEntry, FormatError> {
+        self.reader.seek(SeekFrom::Start(offset))?;
+        HashEntry::parse(&mut self.reader)
+    }
+
+    fn read_top_table(&mut self) -> Result<Vec<HashEntry>, FormatError> {
+        let allocated = self.volume.allocated_block_count;
+        let entry_count = match self.layout.top_level {
+            HashLevel::Zero => allocated,
+            HashLevel::One => allocated.div_ceil(HASHES_PER_TABLE),
+            HashLevel::Two => allocated.div_ceil(LEVEL1_BLOCK_SPAN),
+        };
+
+        let offset = self.hash_table_offset(0, self.layout.top_level)?;
+        self.reader.seek(SeekFrom::Start(offset))?;
+
+        (0..cmp::min(entry_count, HASHES_PER_TABLE))
+            .map(|_| HashEntry::parse(&mut self.reader))
+            .collect()
+    }
+
+    fn read_file_table(&mut self) -> Result<Vec<StfsFileEntry>, FormatError> {
+        let mut files = vec![];
+        let mut block = self.volume.file_table_block_num;
+        let mut block_data = vec![0u8; BLOCK_SIZE as usize];
+
+        for table_block in 0..self.volume.file_table_block_count as u32 {
+            if block >= self.volume.allocated_block_count {
+                debug!("File table block 0x{:X} is outside of the package", block);
+                break;
+            }
+
+            self.read_block(block, &mut block_data)?;
+
+            for (i, entry_data) in block_data.chunks_exact(FILE_ENTRY_SIZE).enumerate() {
+                let index = table_block * FILE_ENTRIES_PER_BLOCK + i as u32;
+                if let Some(entry) = StfsFileEntry::parse(entry_data, index)? {
+                    files.push(entry);
+                }
+            }
+
+            block = self.hash_entry(block)?.next_block;
+        }
+
+        Ok(files)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout(sex: PackageSex, allocated_block_count: u32) -> StfsLayout {
+        StfsLayout {
+            sex,
+            // Header size of 0x971A, as used by most packages
+            first_hash_table_offset: 0xA000,
+            top_level: HashLevel::for_block_count(allocated_block_count),
+            allocated_block_count,
+        }
+    }
+
+    #[test]
+    fn female_block_offsets() {
+        // The level 0 table, 0xAA data blocks, then the level 1 table and the
+        // next level 0 table
+        let layout = layout(PackageSex::Female, 0x100);
+
+        assert_eq!(layout.block_to_offset(0), 0xB000);
+        assert_eq!(layout.block_to_offset(0xA9), 0xB4000);
+        assert_eq!(layout.block_to_offset(0xAA), 0xB7000);
+        assert_eq!(layout.hash_table_offset(0, HashLevel::Zero), 0xA000);
+        assert_eq!(layout.hash_table_offset(0xAA, HashLevel::Zero), 0xB6000);
+        assert_eq!(layout.hash_table_offset(0xAA, HashLevel::One), 0xB5000);
+        assert_eq!(
+            layout.package_size(),
+            layout.block_to_offset(0xFF) + BLOCK_SIZE
+        );
+    }
+
+    #[test]
+    fn male_block_offsets() {
+        // Same as female packages, but every hash table takes up two blocks
+        let layout = layout(PackageSex::Male, 0x100);
+
+        assert_eq!(layout.block_to_offset(0), 0xC000);
+        assert_eq!(layout.block_to_offset(0xA9), 0xB5000);
+        assert_eq!(layout.block_to_offset(0xAA), 0xBA000);
+        assert_eq!(layout.hash_table_offset(0, HashLevel::Zero), 0xA000);
+        assert_eq!(layout.hash_table_offset(0xAA, HashLevel::Zero), 0xB8000);
+        assert_eq!(layout.hash_table_offset(0xAA, HashLevel::One), 0xB6000);
+    }
+
+    #[test]
+    fn huge_block_numbers_do_not_overflow() {
+        let layout = layout(PackageSex::Male, u32::MAX);
+        assert!(layout.package_size() > u32::MAX as u64);
+        assert!(layout.hash_table_offset(u32::MAX, HashLevel::Zero) > u32::MAX as u64);
+    }
+
+    #[test]
+    fn empty_package_size() {
+        assert_eq!(layout(PackageSex::Female, 0).package_size(), 0xB000);
+        assert_eq!(layout(PackageSex::Male, 0).package_size(), 0xC000);
+    }
+}
@@ -0,0 +1,275 @@
+//! Reassembly of the disc images stored in SVOD packages.
+//!
+//! The package header lives in its own file, and the image is split across
+//! `Data0000`, `Data0001`, ... files in a `.data` directory next to it. Each
+//! data file starts with a master hash block followed by groups of a sub hash
+//! block and 0xCC data blocks.
+
+use super::{SvodVolumeDescriptor, XContentHeader, BLOCK_SIZE};
+use crate::errors::FormatError;
+use std::cmp;
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// Data blocks covered by each sub hash block
+const BLOCKS_PER_GROUP: u64 = 0xCC;
+/// Groups of data blocks in each data file
+const GROUPS_PER_FILE: u64 = 0xCB;
+const BLOCKS_PER_FILE: u64 = BLOCKS_PER_GROUP * GROUPS_PER_FILE;
+
+/// Returns true if `name` looks like one of the numbered data files
+pub fn is_data_file_name(name: &str) -> bool {
+    name.len() == 8
+        && name[..4].eq_ignore_ascii_case("Data")
+        && name[4..].bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Read and Seek over the disc image stored in an SVOD package's data files.
+/// The image is laid out so that the XDVDFS volume descriptor is at the usual
+/// offset of 0x10000.
+pub struct SvodReader<R> {
+    data_files: Vec<R>,
+    descriptor: SvodVolumeDescriptor,
+    /// Offset of the first data block in the image. Everything in front of it
+    /// reads as zeroes.
+    data_start: u64,
+    position: u64,
+}
+
+impl<R: Read + Seek> SvodReader<R> {
+    /// `data_files` must be in order, starting with `Data0000`
+    pub fn new(header: &XContentHeader, data_files: Vec<R>) -> Result<SvodReader<R>, FormatError> {
+        match header.svod_volume_descriptor() {
+            Some(descriptor) => SvodReader::with_descriptor(descriptor.clone(), data_files),
+            None => Err(FormatError::Unsupported {
+                format: "SVOD",
+                reason: "package uses an STFS volume descriptor",
+            }),
+        }
+    }
+
+    fn with_descriptor(
+        descriptor: SvodVolumeDescriptor,
+        data_files: Vec<R>,
+    ) -> Result<SvodReader<R>, FormatError> {
+        let needed_files = (descriptor.data_block_count as u64).div_ceil(BLOCKS_PER_FILE);
+        if (data_files.len() as u64) < needed_files {
+            return Err(FormatError::InvalidHeader {
+                format: "SVOD",
+                reason: "package is missing some of its data files",
+            });
+        }
+
+        // The first data block sits `data_block_offset` blocks into the image.
+        // Images with the enhanced GDF layout are shifted back by a block,
+        // which puts their XDVDFS volume descriptor in the first data block.
+        let offset = descriptor.data_block_offset as u64;
+        let data_start_block = if descriptor.has_enhanced_gdf_layout() {
+            offset.checked_sub(1).ok_or(FormatError::InvalidHeader {
+                format: "SVOD",
+                reason: "data block offset is too small for the enhanced GDF layout",
+            })?
+        } else {
+            offset
+        };
+        let data_start = data_start_block * BLOCK_SIZE;
+
+        Ok(SvodReader {
+            data_files,
+            descriptor,
+            data_start,
+            position: 0,
+        })
+    }
+
+    pub fn descriptor(&self) -> &SvodVolumeDescriptor {
+        &self.descriptor
+    }
+
+    /// Length of the reassembled image
+    pub fn len(&self) -> u64 {
+        self.data_start + self.descriptor.data_block_count as u64 * BLOCK_SIZE
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Data file and offset inside of it that hold a data block
+    fn block_location(block: u64) -> (usize, u64) {
+        let file = block / BLOCKS_PER_FILE;
+        let block = block % BLOCKS_PER_FILE;
+        let group = block / BLOCKS_PER_GROUP;
+
+        // Skip the master hash block, the preceding groups and this group's
+        // sub hash block
+        let offset = BLOCK_SIZE
+            + group * (BLOCKS_PER_GROUP + 1) * BLOCK_SIZE
+            + BLOCK_SIZE
+            + (block % BLOCKS_PER_GROUP) * BLOCK_SIZE;
+
+        (file as usize, offset)
+    }
+}
+
+impl<R: Read + Seek> Read for SvodReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        let remaining = self.len().saturating_sub(self.position);
+        let len = cmp::min(buf.len() as u64, remaining) as usize;
+        if len == 0 {
+            return Ok(0);
+        }
+
+        if self.position < self.data_start {
+            let len = cmp::min(len as u64, self.data_start - self.position) as usize;
+            buf[..len].fill(0);
+
+            self.position += len as u64;
+            return Ok(len);
+        }
+
+        // Only read up to the end of the current block since the next one may
+        // be in another group or file
+        let image_offset = self.position - self.data_start;
+        let block_offset = image_offset % BLOCK_SIZE;
+        let len = cmp::min(len as u64, BLOCK_SIZE - block_offset) as usize;
+
+        let (file, offset) = Self::block_location(image_offset / BLOCK_SIZE);
+        let data_file = &mut self.data_files[file];
+        data_file.seek(SeekFrom::Start(offset + block_offset))?;
+        data_file.read_exact(&mut buf[..len])?;
+
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl<R: Read + Seek> Seek for SvodReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, io::Error> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len().checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+
+        match position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::xdvdfs;
+    use std::io::Cursor;
+
+    fn descriptor(
+        features: u8,
+        data_block_count: u32,
+        data_block_offset: u32,
+    ) -> SvodVolumeDescriptor {
+        SvodVolumeDescriptor {
+            block_cache_element_count: 0,
+            worker_thread_processor: 0,
+            worker_thread_priority: 0,
+            root_hash: [0; 0x14],
+            features,
+            data_block_count,
+            data_block_offset,
+        }
+    }
+
+    /// Builds a data file holding `block_count` data blocks, each filled with
+    /// the low byte of its index. Hash blocks are filled with 0xFF.
+    fn data_file(block_count: u64) -> Vec<u8> {
+        let mut data = vec![0xFF; BLOCK_SIZE as usize];
+        for block in 0..block_count {
+            if block % BLOCKS_PER_GROUP == 0 {
+                data.extend(vec![0xFF; BLOCK_SIZE as usize]);
+            }
+            data.extend(vec![block as u8; BLOCK_SIZE as usize]);
+        }
+
+        data
+    }
+
+    fn read_at<R: Read + Seek>(reader: &mut SvodReader<R>, offset: u64, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        reader.seek(SeekFrom::Start(offset)).unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn enhanced_gdf_layout_starts_at_volume_descriptor() {
+        // Games on Demand packages with the enhanced GDF layout store the
+        // volume descriptor at 0x2000 in Data0000, right after the master and
+        // first sub hash blocks, with a data block offset of 0x11
+        let block_count = BLOCKS_PER_GROUP + 2;
+        let mut data = data_file(block_count);
+        data[0x2000..0x2000 + xdvdfs::MAGIC.len()].copy_from_slice(xdvdfs::MAGIC);
+
+        let mut reader = SvodReader::with_descriptor(
+            descriptor(0x40, block_count as u32, 0x11),
+            vec![Cursor::new(data)],
+        )
+        .unwrap();
+
+        assert_eq!(reader.len(), 0x10000 + block_count * BLOCK_SIZE);
+        assert_eq!(read_at(&mut reader, 0xFFF0, 0x10), vec![0; 0x10]);
+        assert_eq!(
+            read_at(&mut reader, 0x10000, xdvdfs::MAGIC.len()),
+            xdvdfs::MAGIC
+        );
+        // The first block of the second group comes after its sub hash block
+        assert_eq!(
+            read_at(&mut reader, 0x10000 + BLOCKS_PER_GROUP * BLOCK_SIZE, 1),
+            vec![BLOCKS_PER_GROUP as u8]
+        );
+    }
+
+    #[test]
+    fn original_layout_starts_at_data_block_offset() {
+        // Without the enhanced GDF layout the volume descriptor is at 0x12000
+        // in Data0000 when the data block offset is 0
+        let block_count = 0x20;
+        let mut data = data_file(block_count);
+        data[0x12000..0x12000 + xdvdfs::MAGIC.len()].copy_from_slice(xdvdfs::MAGIC);
+
+        let mut reader = SvodReader::with_descriptor(
+            descriptor(0, block_count as u32, 0),
+            vec![Cursor::new(data.clone())],
+        )
+        .unwrap();
+        assert_eq!(
+            read_at(&mut reader, 0x10000, xdvdfs::MAGIC.len()),
+            xdvdfs::MAGIC
+        );
+
+        // Each block of offset moves the data further into the image
+        let mut reader = SvodReader::with_descriptor(
+            descriptor(0, block_count as u32, 2),
+            vec![Cursor::new(data)],
+        )
+        .unwrap();
+        assert_eq!(reader.len(), (2 + block_count) * BLOCK_SIZE);
+        assert_eq!(
+            read_at(&mut reader, 0x12000, xdvdfs::MAGIC.len()),
+            xdvdfs::MAGIC
+        );
+    }
+
+    #[test]
+    fn enhanced_gdf_layout_rejects_zero_offset() {
+        let result =
+            SvodReader::with_descriptor(descriptor(0x40, 1, 0), vec![Cursor::new(data_file(1))]);
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,163 @@
+//! Verification of a package's blocks against its hash tables.
+
+use super::{
+    HashLevel, StfsFileEntry, StfsPackage, BLOCK_SIZE, HASHES_PER_TABLE, LEVEL1_BLOCK_SPAN,
+};
+use crate::errors::FormatError;
+use sha1::Sha1;
+use std::cmp;
+use std::io::{Read, Seek, SeekFrom};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockStatus {
+    /// The block's SHA-1 matches its hash entry
+    Valid,
+    /// The block's SHA-1 doesn't match its hash entry, so it has most likely
+    /// been overwritten
+    HashMismatch,
+    /// The block or its hash entry couldn't be read, usually because the
+    /// package is truncated
+    Unreadable,
+}
+
+/// Result of checking a single hash table against the entry that describes
+/// it, or against the volume descriptor for the top table
+#[derive(Debug, Clone)]
+pub struct HashTableStatus {
+    pub level: HashLevel,
+    /// Index of the table among the tables at its level
+    pub index: u32,
+    pub status: BlockStatus,
+}
+
+/// Integrity of every hash table and data block in a package
+#[derive(Debug, Clone)]
+pub struct Verification {
+    hash_tables: Vec<HashTableStatus>,
+    blocks: Vec<BlockStatus>,
+}
+
+impl Verification {
+    /// Hash tables from level 0 up to the top table, which is checked against
+    /// the hash in the volume descriptor
+    pub fn hash_tables(&self) -> &[HashTableStatus] {
+        &self.hash_tables
+    }
+
+    /// Status of every allocated data block, indexed by block number
+    pub fn blocks(&self) -> &[BlockStatus] {
+        &self.blocks
+    }
+
+    pub fn block_status(&self, block: u32) -> BlockStatus {
+        self.blocks
+            .get(block as usize)
+            .copied()
+            .unwrap_or(BlockStatus::Unreadable)
+    }
+
+    /// Whether every hash table and data block is valid
+    pub fn is_intact(&self) -> bool {
+        self.hash_tables
+            .iter()
+            .all(|table| table.status == BlockStatus::Valid)
+            && self.blocks.iter().all(|block| *block == BlockStatus::Valid)
+    }
+
+    /// Number of a file's blocks that are not valid. Fails if the file's block
+    /// chain can't be followed.
+    pub fn bad_file_blocks<R: Read + Seek>(
+        &self,
+        package: &mut StfsPackage<R>,
+        entry: &StfsFileEntry,
+    ) -> Result<usize, FormatError> {
+        Ok(package
+            .file_blocks(entry)?
+            .into_iter()
+            .filter(|block| self.block_status(*block) != BlockStatus::Valid)
+            .count())
+    }
+}
+
+impl<R: Read + Seek> StfsPackage<R> {
+    /// Checks the SHA-1 of every data block against its hash entry and of
+    /// every hash table against the level above it, up to the top hash in the
+    /// volume descriptor
+    pub fn verify(&mut self) -> Result<Verification, FormatError> {
+        let allocated = self.volume.allocated_block_count;
+        let top_level = self.layout.top_level;
+        let mut hash_tables = vec![];
+
+        let levels = [
+            (HashLevel::Zero, HASHES_PER_TABLE),
+            (HashLevel::One, LEVEL1_BLOCK_SPAN),
+            (HashLevel::Two, LEVEL1_BLOCK_SPAN * HASHES_PER_TABLE),
+        ];
+        for &(level, span) in levels.iter().filter(|(l, _)| *l <= top_level) {
+            // An empty package still has a top table
+            for index in 0..cmp::max(allocated.div_ceil(span), 1) {
+                let status = self
+                    .verify_hash_table(index * span, level)
+                    .unwrap_or_else(|e| {
+                        debug!("Could not verify {:?} hash table {}: {}", level, index, e);
+                        BlockStatus::Unreadable
+                    });
+
+                hash_tables.push(HashTableStatus {
+                    level,
+                    index,
+                    status,
+                });
+            }
+        }
+
+        let blocks = (0..allocated)
+            .map(|block| {
+                self.verify_block(block).unwrap_or_else(|e| {
+                    debug!("Could not verify block 0x{:X}: {}", block, e);
+                    BlockStatus::Unreadable
+                })
+            })
+            .collect();
+
+        Ok(Verification {
+            hash_tables,
+            blocks,
+        })
+    }
+
+    fn verify_block(&mut self, block: u32) -> Result<BlockStatus, FormatError> {
+        let mut data = vec![0u8; BLOCK_SIZE as usize];
+        self.read_block(block, &mut data)?;
+        let expected = self.hash_entry(block)?.hash;
+
+        Ok(compare_hash(&data, &expected))
+    }
+
+    fn verify_hash_table(
+        &mut self,
+        block: u32,
+        level: HashLevel,
+    ) -> Result<BlockStatus, FormatError> {
+        let offset = self.hash_table_offset(block, level)?;
+        let mut data = vec![0u8; BLOCK_SIZE as usize];
+        self.reader.seek(SeekFrom::Start(offset))?;
+        self.reader.read_exact(&mut data)?;
+
+        let expected = if level == self.layout.top_level {
+            self.volume.top_hash_table_hash
+        } else {
+            self.parent_hash_entry(block, level)?.hash
+        };
+
+        Ok(compare_hash(&data, &expected))
+    }
+}
+
+fn compare_hash(data: &[u8], expected: &[u8; 0x14]) -> BlockStatus {
+    if Sha1::from(data).digest().bytes() == *expected {
+        BlockStatus::Valid
+    } else {
+        BlockStatus::HashMismatch
+    }
+}
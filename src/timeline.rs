@@ -0,0 +1,194 @@
+//! Timeline export in the Sleuth Kit bodyfile format (version 3.x):
+//!
+//! `MD5|name|inode|mode_as_string|UID|GID|size|atime|mtime|ctime|crtime`
+//!
+//! FATX has no inodes, so the inode column holds the entry's first cluster
+//! followed by its raw attribute byte (e.g. `1234-10`). There's no metadata
+//! change time either, so ctime is always 0.
+
+use crate::fatx::{Directory, Entry, EntryAttributes, Partition};
+use crate::scanners::DeletedFileType;
+use chrono::NaiveDateTime;
+use std::collections::HashSet;
+use std::io::{self, Write};
+
+/// Writes bodyfile lines for partitions and recovered entries
+pub struct BodyfileWriter<W: Write> {
+    out: W,
+    /// Disk offsets of every entry that has been written so that entries
+    /// recovered by the scanner aren't reported twice
+    written_entries: HashSet<u64>,
+    /// Disk offsets of every directory that has been walked. Deleted
+    /// directories may point at clusters that have since been reused, so this
+    /// guards against walking in circles.
+    visited_dirs: HashSet<u64>,
+}
+
+impl<W: Write> BodyfileWriter<W> {
+    pub fn new(out: W) -> Self {
+        BodyfileWriter {
+            out,
+            written_entries: HashSet::new(),
+            visited_dirs: HashSet::new(),
+        }
+    }
+
+    /// Writes a line for every entry reachable from the partition's root
+    /// directory, including entries that are marked as deleted
+    pub fn write_partition(&mut self, partition: &Partition) -> Result<(), io::Error> {
+        let root = partition.root_dir()?;
+        self.visited_dirs
+            .insert(partition.block_offset(partition.root_cluster()));
+
+        self.write_dir(partition, &root, &format!("/{}", partition.name()), false)
+    }
+
+    /// Writes a line for every FATX entry that was recovered by the scanner
+    /// and isn't already part of the directory tree. Since their parent
+    /// directory is unknown they are placed under `$OrphanFiles`.
+    pub fn write_recovered(
+        &mut self,
+        partition: &Partition,
+        deleted_files: &[DeletedFileType],
+    ) -> Result<(), io::Error> {
+        let orphan_path = format!("/{}/$OrphanFiles", partition.name());
+
+        for file in deleted_files {
+            if let DeletedFileType::FatxEntry(entry) = file {
+                self.write_entry_tree(partition, &orphan_path, entry, true)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    fn write_dir(
+        &mut self,
+        partition: &Partition,
+        dir: &Directory,
+        path: &str,
+        parent_deleted: bool,
+    ) -> Result<(), io::Error> {
+        for entry in dir.entries() {
+            let deleted = parent_deleted || entry.is_deleted();
+            self.write_entry_tree(partition, path, entry, deleted)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_entry_tree(
+        &mut self,
+        partition: &Partition,
+        parent_path: &str,
+        entry: &Entry,
+        deleted: bool,
+    ) -> Result<(), io::Error> {
+        if !self.written_entries.insert(entry.offset()) {
+            return Ok(());
+        }
+
+        self.write_entry(parent_path, entry, deleted)?;
+
+        if entry.is_dir()
+            && entry.block() != 0
+            && self
+                .visited_dirs
+                .insert(partition.block_offset(entry.block()))
+        {
+            let path = format!("{}/{}", parent_path, entry.name());
+            match Directory::parse(entry, partition, entry.name().to_owned()) {
+                Ok(dir) => self.write_dir(partition, &dir, &path, deleted)?,
+                Err(e) => debug!("Could not parse directory {}: {}", path, e),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_entry(
+        &mut self,
+        parent_path: &str,
+        entry: &Entry,
+        deleted: bool,
+    ) -> Result<(), io::Error> {
+        writeln!(
+            self.out,
+            "0|{}/{}{}|{}-{:X}|{}|0|0|{}|{}|{}|0|{}",
+            parent_path,
+            entry.name(),
+            if deleted { " (deleted)" } else { "" },
+            entry.block(),
+            entry.attributes().bits(),
+            mode_string(entry),
+            entry.size(),
+            unix_time(entry.accessed()),
+            unix_time(entry.modified()),
+            unix_time(entry.created()),
+        )
+    }
+}
+
+fn mode_string(entry: &Entry) -> &'static str {
+    let read_only = entry.attributes().contains(EntryAttributes::READONLY);
+
+    match (entry.is_dir(), read_only) {
+        (true, false) => "d/drwxrwxrwx",
+        (true, true) => "d/dr-xr-xr-x",
+        (false, false) => "r/rrwxrwxrwx",
+        (false, true) => "r/rr-xr-xr-x",
+    }
+}
+
+fn unix_time(timestamp: Option<NaiveDateTime>) -> i64 {
+    timestamp.map(|t| t.and_utc().timestamp()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fatx::tests::directory_image;
+    use chrono::NaiveDate;
+
+    #[test]
+    fn bodyfile_marks_deleted_entries_and_has_timestamps() {
+        let data = directory_image();
+        let partition = Partition::new(&data, 0, data.len(), "test").unwrap();
+
+        let mut bodyfile = BodyfileWriter::new(vec![]);
+        bodyfile.write_partition(&partition).unwrap();
+        let output = String::from_utf8(bodyfile.into_inner()).unwrap();
+
+        let time = NaiveDate::from_ymd_opt(2025, 1, 11)
+            .and_then(|d| d.and_hms_opt(12, 0, 30))
+            .unwrap()
+            .and_utc()
+            .timestamp();
+        let lines: Vec<_> = output.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                format!(
+                    "0|/test/Content|2-10|d/drwxrwxrwx|0|0|0|{0}|{0}|0|{0}",
+                    time
+                ),
+                format!(
+                    "0|/test/Content/Sub.bin|5-0|r/rrwxrwxrwx|0|0|16|{0}|{0}|0|{0}",
+                    time
+                ),
+                format!(
+                    "0|/test/hello.txt|3-0|r/rrwxrwxrwx|0|0|5|{0}|{0}|0|{0}",
+                    time
+                ),
+                format!(
+                    "0|/test/gone.txt (deleted)|4-0|r/rrwxrwxrwx|0|0|5|{0}|{0}|0|{0}",
+                    time
+                ),
+            ]
+        );
+    }
+}
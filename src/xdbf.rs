@@ -0,0 +1,532 @@
+//! Parsing of XDBF databases, which hold profiles (GPD files) and the
+//! achievements, settings and images of each title a profile has played.
+//!
+//! An XDBF file has a header followed by an entry table and a free space
+//! table, each with a fixed number of slots. Entries point at records in the
+//! data area that follows the tables, and their namespace says how the record
+//! is laid out. Everything is big-endian.
+
+use crate::errors::FormatError;
+use crate::fatx;
+use byteorder::{BigEndian, ReadBytesExt};
+use chrono::NaiveDateTime;
+use std::io::{Cursor, Read};
+
+pub const MAGIC: &[u8] = b"XDBF";
+const VERSION: u32 = 0x10000;
+const HEADER_SIZE: usize = 0x18;
+const ENTRY_SIZE: usize = 0x12;
+const FREE_SPACE_ENTRY_SIZE: usize = 0x8;
+/// Sanity limit on the size of the entry and free space tables
+const MAX_TABLE_LENGTH: u32 = 0x10000;
+/// Entries with these IDs hold sync bookkeeping rather than records
+const SYNC_LIST_ID: u64 = 0x1_0000_0000;
+const SYNC_DATA_ID: u64 = 0x2_0000_0000;
+/// Achievement flag set once the achievement has been unlocked
+const ACHIEVEMENT_UNLOCKED: u32 = 0x20000;
+/// Achievement flag set if the unlock was synced with Xbox Live
+const ACHIEVEMENT_UNLOCKED_ONLINE: u32 = 0x10000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Namespace {
+    Achievement,
+    Image,
+    Setting,
+    Title,
+    String,
+    AvatarAward,
+    Unknown(u16),
+}
+
+impl From<u16> for Namespace {
+    fn from(namespace: u16) -> Namespace {
+        match namespace {
+            1 => Namespace::Achievement,
+            2 => Namespace::Image,
+            3 => Namespace::Setting,
+            4 => Namespace::Title,
+            5 => Namespace::String,
+            6 => Namespace::AvatarAward,
+            other => Namespace::Unknown(other),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct XdbfEntry {
+    pub namespace: Namespace,
+    pub id: u64,
+    /// Offset of the record relative to the start of the data area
+    pub offset: u32,
+    pub length: u32,
+}
+
+impl XdbfEntry {
+    /// Whether the entry holds sync bookkeeping instead of a record
+    pub fn is_sync_entry(&self) -> bool {
+        self.id == SYNC_LIST_ID || self.id == SYNC_DATA_ID
+    }
+}
+
+/// A region of the data area that is not used by any entry. Deleted records
+/// may still be found in these.
+#[derive(Debug, Clone)]
+pub struct FreeSpaceEntry {
+    /// Offset relative to the start of the data area
+    pub offset: u32,
+    pub length: u32,
+}
+
+impl FreeSpaceEntry {
+    /// The last free space entry covers everything up to the end of the file,
+    /// and its length is stored as the inverse of its offset
+    pub fn runs_to_end(&self) -> bool {
+        self.length == !self.offset
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Achievement {
+    pub id: u32,
+    pub image_id: u32,
+    pub gamerscore: i32,
+    pub flags: u32,
+    pub unlock_time: Option<NaiveDateTime>,
+    pub label: String,
+    pub description: String,
+    pub unachieved_description: String,
+}
+
+impl Achievement {
+    pub fn is_unlocked(&self) -> bool {
+        self.flags & ACHIEVEMENT_UNLOCKED != 0
+    }
+
+    pub fn is_unlocked_online(&self) -> bool {
+        self.flags & ACHIEVEMENT_UNLOCKED_ONLINE != 0
+    }
+}
+
+/// A title that the profile has played, found in the profile's own GPD
+#[derive(Debug, Clone)]
+pub struct Title {
+    pub title_id: u32,
+    pub achievement_count: u32,
+    pub achievements_unlocked: u32,
+    pub gamerscore_total: u32,
+    pub gamerscore_unlocked: u32,
+    pub achievements_unlocked_online: u16,
+    pub flags: u32,
+    pub last_played: Option<NaiveDateTime>,
+    pub name: String,
+}
+
+#[derive(Debug, Clone)]
+pub enum SettingValue {
+    Context(u32),
+    Int32(i32),
+    Int64(i64),
+    Double(f64),
+    Unicode(String),
+    Float(f32),
+    Binary(Vec<u8>),
+    DateTime(Option<NaiveDateTime>),
+    Null,
+    Unknown(u8),
+}
+
+#[derive(Debug, Clone)]
+pub struct Setting {
+    pub id: u32,
+    pub value: SettingValue,
+}
+
+#[derive(Debug, Clone)]
+pub enum Record {
+    Achievement(Achievement),
+    /// A PNG image
+    Image(Vec<u8>),
+    Setting(Setting),
+    Title(Title),
+    String(String),
+    /// Sync bookkeeping, avatar awards and unknown namespaces
+    Other(Vec<u8>),
+}
+
+#[derive(Debug, Clone)]
+pub struct XdbfFile {
+    version: u32,
+    entry_table_length: u32,
+    free_space_table_length: u32,
+    entries: Vec<XdbfEntry>,
+    free_space: Vec<FreeSpaceEntry>,
+}
+
+impl XdbfFile {
+    /// Parses the header and tables at the start of `data`
+    pub fn parse(data: &[u8]) -> Result<XdbfFile, FormatError> {
+        let mut cursor = Cursor::new(data);
+
+        let mut magic = [0u8; 4];
+        cursor.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(FormatError::InvalidMagic {
+                format: "XDBF",
+                magic: u32::from_be_bytes(magic),
+            });
+        }
+
+        let version = cursor.read_u32::<BigEndian>()?;
+        if version != VERSION {
+            return Err(FormatError::Unsupported {
+                format: "XDBF",
+                reason: "unknown version",
+            });
+        }
+
+        let entry_table_length = cursor.read_u32::<BigEndian>()?;
+        let entry_count = cursor.read_u32::<BigEndian>()?;
+        let free_space_table_length = cursor.read_u32::<BigEndian>()?;
+        let free_space_count = cursor.read_u32::<BigEndian>()?;
+
+        if entry_table_length > MAX_TABLE_LENGTH
+            || free_space_table_length > MAX_TABLE_LENGTH
+            || entry_count > entry_table_length
+            || free_space_count > free_space_table_length
+        {
+            return Err(FormatError::InvalidHeader {
+                format: "XDBF",
+                reason: "entry or free space table is too large",
+            });
+        }
+
+        let entries = (0..entry_count)
+            .map(|_| {
+                Ok(XdbfEntry {
+                    namespace: Namespace::from(cursor.read_u16::<BigEndian>()?),
+                    id: cursor.read_u64::<BigEndian>()?,
+                    offset: cursor.read_u32::<BigEndian>()?,
+                    length: cursor.read_u32::<BigEndian>()?,
+                })
+            })
+            .collect::<Result<Vec<_>, FormatError>>()?;
+
+        cursor.set_position((HEADER_SIZE + entry_table_length as usize * ENTRY_SIZE) as u64);
+        let free_space = (0..free_space_count)
+            .map(|_| {
+                Ok(FreeSpaceEntry {
+                    offset: cursor.read_u32::<BigEndian>()?,
+                    length: cursor.read_u32::<BigEndian>()?,
+                })
+            })
+            .collect::<Result<Vec<_>, FormatError>>()?;
+
+        Ok(XdbfFile {
+            version,
+            entry_table_length,
+            free_space_table_length,
+            entries,
+            free_space,
+        })
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn entries(&self) -> &[XdbfEntry] {
+        &self.entries
+    }
+
+    pub fn free_space(&self) -> &[FreeSpaceEntry] {
+        &self.free_space
+    }
+
+    /// Offset of the data area that entry offsets are relative to
+    pub fn data_offset(&self) -> u64 {
+        (HEADER_SIZE
+            + self.entry_table_length as usize * ENTRY_SIZE
+            + self.free_space_table_length as usize * FREE_SPACE_ENTRY_SIZE) as u64
+    }
+
+    /// Size of the file, which runs to the end of the last record or free
+    /// space region
+    pub fn file_size(&self) -> u64 {
+        let entry_ends = self
+            .entries
+            .iter()
+            .map(|e| e.offset as u64 + e.length as u64);
+        let free_space_ends = self.free_space.iter().map(|f| {
+            if f.runs_to_end() {
+                f.offset as u64
+            } else {
+                f.offset as u64 + f.length as u64
+            }
+        });
+
+        self.data_offset() + entry_ends.chain(free_space_ends).max().unwrap_or(0)
+    }
+
+    /// The raw bytes of an entry's record. `data` is the whole file.
+    pub fn entry_data<'d>(&self, data: &'d [u8], entry: &XdbfEntry) -> Option<&'d [u8]> {
+        let start = self.data_offset() as usize + entry.offset as usize;
+        data.get(start..start + entry.length as usize)
+    }
+
+    /// Parses an entry's record according to its namespace
+    pub fn record(&self, data: &[u8], entry: &XdbfEntry) -> Result<Record, FormatError> {
+        let record_data = self
+            .entry_data(data, entry)
+            .ok_or(FormatError::InvalidHeader {
+                format: "XDBF",
+                reason: "entry points past the end of the file",
+            })?;
+
+        if entry.is_sync_entry() {
+            return Ok(Record::Other(record_data.to_vec()));
+        }
+
+        let mut cursor = Cursor::new(record_data);
+        let record = match entry.namespace {
+            Namespace::Achievement => Record::Achievement(parse_achievement(&mut cursor)?),
+            Namespace::Image => Record::Image(record_data.to_vec()),
+            Namespace::Setting => Record::Setting(parse_setting(&mut cursor)?),
+            Namespace::Title => Record::Title(parse_title(&mut cursor)?),
+            Namespace::String => Record::String(read_utf16_nul(&mut cursor)?),
+            Namespace::AvatarAward | Namespace::Unknown(_) => Record::Other(record_data.to_vec()),
+        };
+
+        Ok(record)
+    }
+}
+
+fn parse_achievement(cursor: &mut Cursor<&[u8]>) -> Result<Achievement, FormatError> {
+    let _struct_size = cursor.read_u32::<BigEndian>()?;
+
+    Ok(Achievement {
+        id: cursor.read_u32::<BigEndian>()?,
+        image_id: cursor.read_u32::<BigEndian>()?,
+        gamerscore: cursor.read_i32::<BigEndian>()?,
+        flags: cursor.read_u32::<BigEndian>()?,
+        unlock_time: fatx::decode_filetime(cursor.read_u64::<BigEndian>()?),
+        label: read_utf16_nul(cursor)?,
+        description: read_utf16_nul(cursor)?,
+        unachieved_description: read_utf16_nul(cursor)?,
+    })
+}
+
+fn parse_title(cursor: &mut Cursor<&[u8]>) -> Result<Title, FormatError> {
+    let title_id = cursor.read_u32::<BigEndian>()?;
+    let achievement_count = cursor.read_u32::<BigEndian>()?;
+    let achievements_unlocked = cursor.read_u32::<BigEndian>()?;
+    let gamerscore_total = cursor.read_u32::<BigEndian>()?;
+    let gamerscore_unlocked = cursor.read_u32::<BigEndian>()?;
+    let achievements_unlocked_online = cursor.read_u16::<BigEndian>()?;
+    // Avatar award counts
+    let mut _avatar_awards = [0u8; 6];
+    cursor.read_exact(&mut _avatar_awards)?;
+    let flags = cursor.read_u32::<BigEndian>()?;
+    let last_played = fatx::decode_filetime(cursor.read_u64::<BigEndian>()?);
+
+    Ok(Title {
+        title_id,
+        achievement_count,
+        achievements_unlocked,
+        gamerscore_total,
+        gamerscore_unlocked,
+        achievements_unlocked_online,
+        flags,
+        last_played,
+        name: read_utf16_nul(cursor)?,
+    })
+}
+
+fn parse_setting(cursor: &mut Cursor<&[u8]>) -> Result<Setting, FormatError> {
+    let id = cursor.read_u32::<BigEndian>()?;
+    let _padding = cursor.read_u32::<BigEndian>()?;
+    let setting_type = cursor.read_u8()?;
+    let mut _padding = [0u8; 7];
+    cursor.read_exact(&mut _padding)?;
+
+    // The value is stored inline in the next 8 bytes, or as a length followed
+    // by the data for strings and binary settings
+    let value = match setting_type {
+        0 => SettingValue::Context(cursor.read_u32::<BigEndian>()?),
+        1 => SettingValue::Int32(cursor.read_i32::<BigEndian>()?),
+        2 => SettingValue::Int64(cursor.read_i64::<BigEndian>()?),
+        3 => SettingValue::Double(cursor.read_f64::<BigEndian>()?),
+        4 | 6 => {
+            let len = cursor.read_u32::<BigEndian>()?;
+            let _padding = cursor.read_u32::<BigEndian>()?;
+
+            let remaining = cursor.get_ref().len() as u64 - cursor.position();
+            if len as u64 > remaining {
+                return Err(FormatError::InvalidHeader {
+                    format: "XDBF",
+                    reason: "setting value runs past the end of its record",
+                });
+            }
+
+            let mut data = vec![0u8; len as usize];
+            cursor.read_exact(&mut data)?;
+
+            if setting_type == 4 {
+                SettingValue::Unicode(read_utf16_nul(&mut Cursor::new(&data[..]))?)
+            } else {
+                SettingValue::Binary(data)
+            }
+        }
+        5 => SettingValue::Float(cursor.read_f32::<BigEndian>()?),
+        7 => SettingValue::DateTime(fatx::decode_filetime(cursor.read_u64::<BigEndian>()?)),
+        0xFF => SettingValue::Null,
+        other => SettingValue::Unknown(other),
+    };
+
+    Ok(Setting { id, value })
+}
+
+/// Reads a UTF-16BE string up to a null terminator or the end of the data
+fn read_utf16_nul(cursor: &mut Cursor<&[u8]>) -> Result<String, FormatError> {
+    let mut chars = vec![];
+    while let Ok(c) = cursor.read_u16::<BigEndian>() {
+        if c == 0 {
+            break;
+        }
+
+        chars.push(c);
+    }
+
+    Ok(String::from_utf16_lossy(&chars))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Offset of the data area of `gpd_file`, with room for four entries and
+    /// two free space regions
+    const DATA_OFFSET: usize = HEADER_SIZE + 4 * ENTRY_SIZE + 2 * FREE_SPACE_ENTRY_SIZE;
+
+    fn setting(id: u32, setting_type: u8, value: &[u8]) -> Vec<u8> {
+        let mut data = id.to_be_bytes().to_vec();
+        data.extend(&[0; 4]);
+        data.push(setting_type);
+        data.extend(&[0; 7]);
+        data.extend(value);
+        data
+    }
+
+    /// A GPD holding an integer setting and a string setting, followed by a
+    /// free region and the free space at the end of the file
+    fn gpd_file() -> Vec<u8> {
+        let mut records = setting(0x1004_0001, 1, &[0xFF, 0xFF, 0xFF, 0xFE, 0, 0, 0, 0]);
+        let string_offset = records.len() as u32;
+        let mut value = 6u32.to_be_bytes().to_vec();
+        value.extend(&[0; 4]);
+        value.extend(&[0, b'h', 0, b'i', 0, 0]);
+        records.extend(setting(0x4064_0010, 4, &value));
+
+        let mut data = MAGIC.to_vec();
+        for value in [VERSION, 4, 2, 2, 2].iter() {
+            data.extend(&value.to_be_bytes());
+        }
+
+        let entries = [
+            (0x1004_0001, 0, string_offset),
+            (
+                0x4064_0010,
+                string_offset,
+                records.len() as u32 - string_offset,
+            ),
+        ];
+        for &(id, offset, length) in entries.iter() {
+            data.extend(&3u16.to_be_bytes());
+            data.extend(&(id as u64).to_be_bytes());
+            data.extend(&offset.to_be_bytes());
+            data.extend(&length.to_be_bytes());
+        }
+        data.resize(HEADER_SIZE + 4 * ENTRY_SIZE, 0);
+
+        for &(offset, length) in [(0x40u32, 0x10u32), (0x50, !0x50)].iter() {
+            data.extend(&offset.to_be_bytes());
+            data.extend(&length.to_be_bytes());
+        }
+
+        data.extend(records);
+        data.resize(DATA_OFFSET + 0x50, 0);
+        data
+    }
+
+    #[test]
+    fn tables_are_parsed() {
+        let gpd = XdbfFile::parse(&gpd_file()).unwrap();
+
+        assert_eq!(gpd.data_offset(), DATA_OFFSET as u64);
+        let entries: Vec<_> = gpd
+            .entries()
+            .iter()
+            .map(|e| (e.namespace, e.id, e.offset, e.length))
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                (Namespace::Setting, 0x1004_0001, 0, 0x18),
+                (Namespace::Setting, 0x4064_0010, 0x18, 0x1E)
+            ]
+        );
+
+        let free_space = gpd.free_space();
+        assert_eq!(free_space.len(), 2);
+        assert!(!free_space[0].runs_to_end());
+        assert!(free_space[1].runs_to_end());
+    }
+
+    #[test]
+    fn file_size_ends_at_last_free_space() {
+        let data = gpd_file();
+        let gpd = XdbfFile::parse(&data).unwrap();
+        assert_eq!(gpd.file_size(), data.len() as u64);
+    }
+
+    #[test]
+    fn settings_are_decoded() {
+        let data = gpd_file();
+        let gpd = XdbfFile::parse(&data).unwrap();
+
+        match gpd.record(&data, &gpd.entries()[0]).unwrap() {
+            Record::Setting(Setting {
+                id: 0x1004_0001,
+                value: SettingValue::Int32(-2),
+            }) => {}
+            other => panic!("unexpected record {:?}", other),
+        }
+        match gpd.record(&data, &gpd.entries()[1]).unwrap() {
+            Record::Setting(Setting {
+                value: SettingValue::Unicode(value),
+                ..
+            }) => assert_eq!(value, "hi"),
+            other => panic!("unexpected record {:?}", other),
+        }
+    }
+
+    #[test]
+    fn oversized_setting_length_is_rejected() {
+        let mut data = gpd_file();
+        let length = DATA_OFFSET + 0x18 + 0x10;
+        data[length..length + 4].copy_from_slice(&0x100u32.to_be_bytes());
+        let gpd = XdbfFile::parse(&data).unwrap();
+
+        assert!(matches!(
+            gpd.record(&data, &gpd.entries()[1]),
+            Err(FormatError::InvalidHeader { .. })
+        ));
+    }
+
+    #[test]
+    fn oversized_tables_are_rejected() {
+        let mut data = gpd_file();
+        // More entries than the table has room for
+        data[0xC..0x10].copy_from_slice(&5u32.to_be_bytes());
+        assert!(XdbfFile::parse(&data).is_err());
+    }
+}
@@ -0,0 +1,487 @@
+//! Parsing of XDVDFS, the filesystem used on Xbox and Xbox 360 game discs.
+//!
+//! The volume descriptor lives in sector 32 of the game partition. Each
+//! directory is stored as a binary tree of entries, where every entry holds
+//! the offsets of its left and right children relative to the start of the
+//! directory.
+
+use crate::errors::FormatError;
+use crate::fatx;
+use byteorder::{LittleEndian, ReadBytesExt};
+use chrono::NaiveDateTime;
+use std::cmp;
+use std::collections::HashSet;
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+
+pub const SECTOR_SIZE: u64 = 0x800;
+pub const MAGIC: &[u8] = b"MICROSOFT*XBOX*MEDIA";
+/// Offset of the volume descriptor from the start of the game partition
+pub const VOLUME_DESCRIPTOR_OFFSET: u64 = 32 * SECTOR_SIZE;
+/// Offsets of the game partition in full disc dumps (XGD1, XGD2 and XGD3),
+/// tried after the start of the image
+const GAME_PARTITION_OFFSETS: [u64; 4] = [0, 0x1830_0000, 0xFD9_0000, 0x208_0000];
+/// Child offset value used for padding at the end of a sector
+const PADDING_OFFSET: u16 = 0xFFFF;
+const DIRENT_HEADER_SIZE: usize = 0xE;
+/// Dirent offsets are 16 bits in units of 4 bytes, so nothing in a directory
+/// past the last addressable entry and its name can be referenced
+const MAX_DIRECTORY_SIZE: u32 = 0xFFFF * 4 + DIRENT_HEADER_SIZE as u32 + 0xFF;
+
+bitflags! {
+    pub struct XdvdfsAttributes: u8 {
+        const READ_ONLY = 0x01;
+        const HIDDEN = 0x02;
+        const SYSTEM = 0x04;
+        const DIRECTORY = 0x10;
+        const ARCHIVE = 0x20;
+        const NORMAL = 0x80;
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct XdvdfsEntry {
+    name: String,
+    attributes: XdvdfsAttributes,
+    sector: u32,
+    size: u32,
+}
+
+impl XdvdfsEntry {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn attributes(&self) -> XdvdfsAttributes {
+        self.attributes
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.attributes.contains(XdvdfsAttributes::DIRECTORY)
+    }
+
+    /// First sector of the entry's data, relative to the game partition
+    pub fn sector(&self) -> u32 {
+        self.sector
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+}
+
+/// An XDVDFS volume read from `R`
+pub struct XdvdfsVolume<R> {
+    reader: R,
+    /// Offset of the game partition in `reader`
+    base_offset: u64,
+    root: XdvdfsEntry,
+    created: Option<NaiveDateTime>,
+}
+
+impl<R: Read + Seek> XdvdfsVolume<R> {
+    /// Opens a volume in a game partition image or a full disc dump
+    pub fn open(mut reader: R) -> Result<XdvdfsVolume<R>, FormatError> {
+        for &offset in GAME_PARTITION_OFFSETS.iter() {
+            if has_magic(&mut reader, offset + VOLUME_DESCRIPTOR_OFFSET)? {
+                return XdvdfsVolume::open_at(reader, offset);
+            }
+        }
+
+        Err(FormatError::InvalidHeader {
+            format: "XDVDFS",
+            reason: "no volume descriptor was found",
+        })
+    }
+
+    /// Opens the volume whose game partition starts at `base_offset`
+    pub fn open_at(mut reader: R, base_offset: u64) -> Result<XdvdfsVolume<R>, FormatError> {
+        reader.seek(SeekFrom::Start(base_offset + VOLUME_DESCRIPTOR_OFFSET))?;
+
+        let mut magic = [0u8; 0x14];
+        reader.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(FormatError::InvalidMagic {
+                format: "XDVDFS",
+                magic: u32::from_be_bytes([magic[0], magic[1], magic[2], magic[3]]),
+            });
+        }
+
+        let root_sector = reader.read_u32::<LittleEndian>()?;
+        let root_size = reader.read_u32::<LittleEndian>()?;
+        let created = fatx::decode_filetime(reader.read_u64::<LittleEndian>()?);
+
+        Ok(XdvdfsVolume {
+            reader,
+            base_offset,
+            root: XdvdfsEntry {
+                name: String::new(),
+                attributes: XdvdfsAttributes::DIRECTORY,
+                sector: root_sector,
+                size: root_size,
+            },
+            created,
+        })
+    }
+
+    pub fn base_offset(&self) -> u64 {
+        self.base_offset
+    }
+
+    pub fn created(&self) -> Option<NaiveDateTime> {
+        self.created
+    }
+
+    pub fn root(&self) -> &XdvdfsEntry {
+        &self.root
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// Reads the entries of a directory, sorted the way they are stored in
+    /// its tree
+    pub fn read_dir(&mut self, dir: &XdvdfsEntry) -> Result<Vec<XdvdfsEntry>, FormatError> {
+        if !dir.is_dir() {
+            return Err(FormatError::NotADirectory(dir.name().to_owned()));
+        }
+
+        if dir.size() == 0 {
+            return Ok(vec![]);
+        }
+
+        if dir.size() > MAX_DIRECTORY_SIZE {
+            return Err(FormatError::InvalidHeader {
+                format: "XDVDFS",
+                reason: "directory is larger than its entries can address",
+            });
+        }
+
+        // Read through `take` so that a corrupt size can't cause a huge
+        // allocation up front
+        let mut data = vec![];
+        self.reader
+            .seek(SeekFrom::Start(self.sector_offset(dir.sector())))?;
+        (&mut self.reader)
+            .take(dir.size() as u64)
+            .read_to_end(&mut data)?;
+        if data.len() < dir.size() as usize {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+
+        let mut entries = vec![];
+        read_dirent_tree(&data, &mut entries)?;
+
+        Ok(entries)
+    }
+
+    /// Finds the entry at `path`. Components are separated by `/` and matched
+    /// case-insensitively.
+    pub fn lookup(&mut self, path: &str) -> Result<XdvdfsEntry, FormatError> {
+        let mut entry = self.root.clone();
+
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            if !entry.is_dir() {
+                return Err(FormatError::NotADirectory(entry.name().to_owned()));
+            }
+
+            entry = self
+                .read_dir(&entry)?
+                .into_iter()
+                .find(|e| e.name().eq_ignore_ascii_case(component))
+                .ok_or_else(|| FormatError::PathNotFound(path.to_owned()))?;
+        }
+
+        Ok(entry)
+    }
+
+    /// Writes the contents of a file to `writer`. File data is always stored
+    /// in consecutive sectors.
+    pub fn write_file<W: Write>(
+        &mut self,
+        entry: &XdvdfsEntry,
+        writer: &mut W,
+    ) -> Result<u64, FormatError> {
+        self.reader
+            .seek(SeekFrom::Start(self.sector_offset(entry.sector())))?;
+
+        let copied = io::copy(&mut (&mut self.reader).take(entry.size() as u64), writer)?;
+        if copied < entry.size() as u64 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+
+        Ok(copied)
+    }
+
+    /// Size of the game partition, worked out from the end of the furthest
+    /// file or directory
+    pub fn partition_size(&mut self) -> Result<u64, FormatError> {
+        let mut end = VOLUME_DESCRIPTOR_OFFSET + SECTOR_SIZE;
+        let mut pending = vec![self.root.clone()];
+        let mut visited = HashSet::new();
+
+        while let Some(entry) = pending.pop() {
+            end = cmp::max(end, entry_end(&entry));
+
+            if entry.is_dir() && visited.insert(entry.sector()) {
+                pending.extend(self.read_dir(&entry)?);
+            }
+        }
+
+        Ok(end)
+    }
+
+    fn sector_offset(&self, sector: u32) -> u64 {
+        self.base_offset + sector as u64 * SECTOR_SIZE
+    }
+}
+
+fn has_magic<R: Read + Seek>(reader: &mut R, offset: u64) -> Result<bool, io::Error> {
+    let mut magic = [0u8; 0x14];
+    reader.seek(SeekFrom::Start(offset))?;
+
+    match reader.read_exact(&mut magic) {
+        Ok(()) => Ok(magic == MAGIC),
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Offset of the end of an entry's data, rounded up to a whole sector
+fn entry_end(entry: &XdvdfsEntry) -> u64 {
+    (entry.sector() as u64 * SECTOR_SIZE + entry.size() as u64).div_ceil(SECTOR_SIZE) * SECTOR_SIZE
+}
+
+/// Walks the dirent tree in order, appending its entries to `entries`. The
+/// tree is walked with an explicit stack since a corrupt tree can be deep.
+fn read_dirent_tree(data: &[u8], entries: &mut Vec<XdvdfsEntry>) -> Result<(), FormatError> {
+    let mut visited = HashSet::new();
+    let mut pending = vec![];
+    let mut next = Some(0);
+
+    loop {
+        // Descend as far left as possible, remembering each entry along with
+        // its right subtree
+        while let Some(offset) = next {
+            if !visited.insert(offset) {
+                return Err(FormatError::InvalidHeader {
+                    format: "XDVDFS",
+                    reason: "directory entry tree is corrupt",
+                });
+            }
+
+            next = match read_dirent(data, offset)? {
+                Some(node) => {
+                    pending.push((node.entry, node.right));
+                    node.left
+                }
+                None => None,
+            };
+        }
+
+        match pending.pop() {
+            Some((entry, right)) => {
+                entries.push(entry);
+                next = right;
+            }
+            None => return Ok(()),
+        }
+    }
+}
+
+/// A dirent along with the offsets of its children in the tree
+struct DirentNode {
+    left: Option<usize>,
+    entry: XdvdfsEntry,
+    right: Option<usize>,
+}
+
+/// Reads the dirent at `offset`. Returns `None` for a padding entry.
+fn read_dirent(data: &[u8], offset: usize) -> Result<Option<DirentNode>, FormatError> {
+    if offset + DIRENT_HEADER_SIZE > data.len() {
+        return Err(FormatError::InvalidHeader {
+            format: "XDVDFS",
+            reason: "directory entry tree is corrupt",
+        });
+    }
+
+    let mut cursor = Cursor::new(&data[offset..]);
+    let left = cursor.read_u16::<LittleEndian>()?;
+    let right = cursor.read_u16::<LittleEndian>()?;
+    if left == PADDING_OFFSET && right == PADDING_OFFSET {
+        // Empty directories consist of a single padding entry
+        return Ok(None);
+    }
+
+    let sector = cursor.read_u32::<LittleEndian>()?;
+    let size = cursor.read_u32::<LittleEndian>()?;
+    let attributes = XdvdfsAttributes::from_bits_truncate(cursor.read_u8()?);
+    let name_len = cursor.read_u8()? as usize;
+
+    let name_start = offset + DIRENT_HEADER_SIZE;
+    let name = data
+        .get(name_start..name_start + name_len)
+        .ok_or(FormatError::InvalidHeader {
+            format: "XDVDFS",
+            reason: "directory entry name runs past the end of the directory",
+        })?;
+
+    // Offsets are stored in units of 4 bytes, with 0 meaning no child
+    let child = |offset: u16| Some(offset as usize * 4).filter(|&offset| offset != 0);
+
+    Ok(Some(DirentNode {
+        left: child(left),
+        entry: XdvdfsEntry {
+            name: String::from_utf8_lossy(name).into_owned(),
+            attributes,
+            sector,
+            size,
+        },
+        right: child(right),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::carvers::XdvdfsCarver;
+    use crate::scanners::Carver;
+
+    const ROOT_SECTOR: u32 = 33;
+    const SUBDIR_SECTOR: u32 = 34;
+
+    fn write_dirent(
+        data: &mut [u8],
+        offset: usize,
+        children: (u16, u16),
+        entry: (&str, XdvdfsAttributes, u32, u32),
+    ) {
+        let (left, right) = children;
+        let (name, attributes, sector, size) = entry;
+        data[offset..offset + 2].copy_from_slice(&left.to_le_bytes());
+        data[offset + 2..offset + 4].copy_from_slice(&right.to_le_bytes());
+        data[offset + 4..offset + 8].copy_from_slice(&sector.to_le_bytes());
+        data[offset + 8..offset + 0xC].copy_from_slice(&size.to_le_bytes());
+        data[offset + 0xC] = attributes.bits();
+        data[offset + 0xD] = name.len() as u8;
+        data[offset + DIRENT_HEADER_SIZE..offset + DIRENT_HEADER_SIZE + name.len()]
+            .copy_from_slice(name.as_bytes());
+    }
+
+    /// Builds a game partition whose root directory holds `b.txt`, with the
+    /// directory `a` to its left and `c.bin` to its right. `a` holds a single
+    /// file, `inner`. The last file ends at sector 41.
+    fn volume() -> Vec<u8> {
+        let mut data = vec![0u8; 41 * SECTOR_SIZE as usize];
+        let descriptor = VOLUME_DESCRIPTOR_OFFSET as usize;
+        data[descriptor..descriptor + MAGIC.len()].copy_from_slice(MAGIC);
+        data[descriptor + 0x14..descriptor + 0x18].copy_from_slice(&ROOT_SECTOR.to_le_bytes());
+        data[descriptor + 0x18..descriptor + 0x1C].copy_from_slice(&0x800u32.to_le_bytes());
+
+        let root = ROOT_SECTOR as usize * SECTOR_SIZE as usize;
+        data[root..root + SECTOR_SIZE as usize]
+            .iter_mut()
+            .for_each(|b| *b = 0xFF);
+        let normal = XdvdfsAttributes::NORMAL;
+        write_dirent(&mut data, root, (5, 10), ("b.txt", normal, 35, 3));
+        let directory = XdvdfsAttributes::DIRECTORY;
+        write_dirent(
+            &mut data,
+            root + 0x14,
+            (0, 0),
+            ("a", directory, SUBDIR_SECTOR, 0x800),
+        );
+        write_dirent(&mut data, root + 0x28, (0, 0), ("c.bin", normal, 40, 0x10));
+
+        let subdir = SUBDIR_SECTOR as usize * SECTOR_SIZE as usize;
+        write_dirent(&mut data, subdir, (0, 0), ("inner", normal, 36, 0x900));
+
+        data[35 * SECTOR_SIZE as usize..][..3].copy_from_slice(b"abc");
+        data
+    }
+
+    fn names(entries: &[XdvdfsEntry]) -> Vec<&str> {
+        entries.iter().map(|e| e.name()).collect()
+    }
+
+    #[test]
+    fn directory_tree_is_read_in_order() {
+        let mut volume = XdvdfsVolume::open(Cursor::new(volume())).unwrap();
+        assert_eq!(volume.base_offset(), 0);
+
+        let root = volume.root().clone();
+        let entries = volume.read_dir(&root).unwrap();
+        assert_eq!(names(&entries), vec!["a", "b.txt", "c.bin"]);
+        assert!(entries[0].is_dir());
+
+        let entry = volume.lookup("/A/inner").unwrap();
+        assert_eq!((entry.sector(), entry.size()), (36, 0x900));
+        assert!(matches!(
+            volume.lookup("a/missing"),
+            Err(FormatError::PathNotFound(_))
+        ));
+
+        let mut contents = vec![];
+        let entry = volume.lookup("b.txt").unwrap();
+        assert_eq!(volume.write_file(&entry, &mut contents).unwrap(), 3);
+        assert_eq!(contents, b"abc");
+    }
+
+    #[test]
+    fn looping_directory_trees_are_rejected() {
+        // The right child of `c.bin` points back at `a`
+        let mut data = volume();
+        let last = ROOT_SECTOR as usize * SECTOR_SIZE as usize + 0x28;
+        data[last + 2..last + 4].copy_from_slice(&5u16.to_le_bytes());
+
+        let mut volume = XdvdfsVolume::open(Cursor::new(data)).unwrap();
+        let root = volume.root().clone();
+        assert!(matches!(
+            volume.read_dir(&root),
+            Err(FormatError::InvalidHeader { .. })
+        ));
+    }
+
+    #[test]
+    fn directory_reads_are_bounded() {
+        let mut volume = XdvdfsVolume::open(Cursor::new(volume())).unwrap();
+        let dir = |sector, size| XdvdfsEntry {
+            name: "dir".to_owned(),
+            attributes: XdvdfsAttributes::DIRECTORY,
+            sector,
+            size,
+        };
+
+        // Bigger than any tree can address
+        assert!(matches!(
+            volume.read_dir(&dir(ROOT_SECTOR, u32::MAX)),
+            Err(FormatError::InvalidHeader { .. })
+        ));
+        // Runs past the end of the image
+        assert!(volume.read_dir(&dir(40, 0x1000)).is_err());
+        assert!(volume.read_dir(&dir(40, 0)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn partition_size_ends_at_furthest_entry() {
+        let mut volume = XdvdfsVolume::open(Cursor::new(volume())).unwrap();
+        assert_eq!(volume.partition_size().unwrap(), 41 * SECTOR_SIZE);
+    }
+
+    #[test]
+    fn carver_starts_before_volume_descriptor() {
+        let mut data = vec![0xAA; 0x8000];
+        data.extend(volume());
+        data.extend(vec![0xAA; 0x8000]);
+
+        let carver = XdvdfsCarver;
+        let file = carver
+            .carve(&data, 0x8000 + VOLUME_DESCRIPTOR_OFFSET as usize)
+            .unwrap();
+        assert_eq!(file.offset(), 0x8000);
+        assert_eq!(file.size(), Some(41 * SECTOR_SIZE));
+
+        // Too close to the start of the disk to have a game partition
+        assert!(carver.carve(&data, 0x8000).is_none());
+    }
+}
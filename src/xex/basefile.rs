@@ -0,0 +1,186 @@
+//! Reading the file format info optional header and rebuilding the basefile
+//! (the embedded PE image) of unencrypted XEX2 files.
+
+use super::XexHeader;
+use crate::errors::FormatError;
+use byteorder::{BigEndian, ReadBytesExt};
+use std::io::{self, Read, Write};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncryptionType {
+    None,
+    Normal,
+    Unknown(u16),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Compression {
+    /// The basefile is stored as-is
+    None,
+    /// Runs of data followed by runs of zeroes that were stripped out
+    Basic(Vec<BasicBlock>),
+    /// LZX compressed blocks, each prefixed by the size and hash of the next
+    Normal {
+        window_size: u32,
+        first_block_size: u32,
+    },
+    /// LZX delta patch against another executable
+    Delta,
+    Unknown(u16),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BasicBlock {
+    pub data_size: u32,
+    pub zero_size: u32,
+}
+
+/// Describes how the basefile is stored
+#[derive(Debug, Clone)]
+pub struct FileFormatInfo {
+    pub encryption: EncryptionType,
+    pub compression: Compression,
+}
+
+impl FileFormatInfo {
+    /// Parses the file format info optional header. `max_size` is the space
+    /// left in the XEX2 header, which the info has to fit in.
+    pub fn parse<R: Read>(reader: &mut R, max_size: u64) -> Result<FileFormatInfo, FormatError> {
+        let info_size = reader.read_u32::<BigEndian>()?;
+        if info_size as u64 > max_size {
+            return Err(FormatError::InvalidHeader {
+                format: "XEX2",
+                reason: "file format info runs past the end of the header",
+            });
+        }
+
+        let encryption = match reader.read_u16::<BigEndian>()? {
+            0 => EncryptionType::None,
+            1 => EncryptionType::Normal,
+            other => EncryptionType::Unknown(other),
+        };
+
+        let compression = match reader.read_u16::<BigEndian>()? {
+            0 => Compression::None,
+            1 => {
+                // The rest of the info is made up of 8-byte block descriptors
+                let block_count = info_size.saturating_sub(8) / 8;
+                let blocks = (0..block_count)
+                    .map(|_| {
+                        Ok(BasicBlock {
+                            data_size: reader.read_u32::<BigEndian>()?,
+                            zero_size: reader.read_u32::<BigEndian>()?,
+                        })
+                    })
+                    .collect::<Result<Vec<_>, FormatError>>()?;
+
+                Compression::Basic(blocks)
+            }
+            2 => Compression::Normal {
+                window_size: reader.read_u32::<BigEndian>()?,
+                first_block_size: reader.read_u32::<BigEndian>()?,
+            },
+            3 => Compression::Delta,
+            other => Compression::Unknown(other),
+        };
+
+        Ok(FileFormatInfo {
+            encryption,
+            compression,
+        })
+    }
+}
+
+/// Writes the basefile of the XEX2 file at the start of `data` to `writer`
+/// and returns the number of bytes written.
+///
+/// Only unencrypted basefiles that are either uncompressed or basic
+/// compressed can be rebuilt. Encrypted, LZX compressed and LZX delta
+/// basefiles result in a `FormatError::Unsupported` error.
+pub fn write_basefile<W: Write>(
+    header: &XexHeader,
+    data: &[u8],
+    writer: &mut W,
+) -> Result<u64, FormatError> {
+    let format = header.file_format().ok_or(FormatError::InvalidHeader {
+        format: "XEX2",
+        reason: "missing file format info",
+    })?;
+
+    match format.encryption {
+        EncryptionType::None => {}
+        EncryptionType::Normal => {
+            return Err(FormatError::Unsupported {
+                format: "XEX2 basefile",
+                reason: "basefile is encrypted",
+            })
+        }
+        EncryptionType::Unknown(_) => {
+            return Err(FormatError::Unsupported {
+                format: "XEX2 basefile",
+                reason: "unknown encryption type",
+            })
+        }
+    }
+
+    let basefile =
+        data.get(header.pe_data_offset() as usize..)
+            .ok_or(FormatError::InvalidHeader {
+                format: "XEX2",
+                reason: "PE data offset is past the end of the file",
+            })?;
+
+    let written = match &format.compression {
+        Compression::None => {
+            let image = basefile
+                .get(..header.image_size() as usize)
+                .ok_or_else(truncated)?;
+            writer.write_all(image)?;
+
+            image.len() as u64
+        }
+        Compression::Basic(blocks) => {
+            let mut position = 0usize;
+            let mut written = 0u64;
+            for block in blocks {
+                let block_data = basefile
+                    .get(position..position + block.data_size as usize)
+                    .ok_or_else(truncated)?;
+                writer.write_all(block_data)?;
+                io::copy(&mut io::repeat(0).take(block.zero_size as u64), writer)?;
+
+                position += block.data_size as usize;
+                written += block.data_size as u64 + block.zero_size as u64;
+            }
+
+            written
+        }
+        Compression::Normal { .. } => {
+            return Err(FormatError::Unsupported {
+                format: "XEX2 basefile",
+                reason: "LZX compression",
+            })
+        }
+        Compression::Delta => {
+            return Err(FormatError::Unsupported {
+                format: "XEX2 basefile",
+                reason: "LZX delta patch",
+            })
+        }
+        Compression::Unknown(_) => {
+            return Err(FormatError::Unsupported {
+                format: "XEX2 basefile",
+                reason: "unknown compression type",
+            })
+        }
+    };
+
+    Ok(written)
+}
+
+fn truncated() -> FormatError {
+    FormatError::InvalidHeader {
+        format: "XEX2",
+        reason: "basefile data is truncated",
+    }
+}
@@ -0,0 +1,314 @@
+//! Parsing of XEX2 executable headers.
+//!
+//! An XEX2 file starts with a fixed header followed by a table of optional
+//! headers (key/value pairs). The basefile (a PE image) starts at
+//! `pe_data_offset` and may be compressed and/or encrypted as described by the
+//! file format info optional header.
+
+use crate::errors::FormatError;
+use byteorder::{BigEndian, ReadBytesExt};
+use std::io::{Cursor, Read, Seek, SeekFrom};
+
+mod basefile;
+
+pub use self::basefile::{write_basefile, BasicBlock, Compression, EncryptionType, FileFormatInfo};
+
+const XEX2_MAGIC: &[u8; 4] = b"XEX2";
+const HEADER_SIZE: usize = 0x18;
+/// Sanity limit on the number of optional headers
+const MAX_OPTIONAL_HEADERS: u32 = 0x40;
+/// Size of the SHA-1 hash that precedes each compressed block
+const BLOCK_HASH_SIZE: u64 = 0x14;
+
+const KEY_FILE_FORMAT_INFO: u32 = 0x0000_03FF;
+const KEY_ORIGINAL_PE_NAME: u32 = 0x0001_83FF;
+const KEY_EXECUTION_ID: u32 = 0x0004_0006;
+
+bitflags! {
+    pub struct ModuleFlags: u32 {
+        const TITLE_MODULE = 0x1;
+        const EXPORTS_TO_TITLE = 0x2;
+        const SYSTEM_DEBUGGER = 0x4;
+        const DLL_MODULE = 0x8;
+        const MODULE_PATCH = 0x10;
+        const PATCH_FULL = 0x20;
+        const PATCH_DELTA = 0x40;
+        const USER_MODE = 0x80;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OptionalHeader {
+    pub key: u32,
+    pub value: u32,
+}
+
+impl OptionalHeader {
+    /// Whether `value` holds the header's data rather than an offset to it
+    pub fn is_inline(&self) -> bool {
+        self.key & 0xFF <= 1
+    }
+}
+
+/// Identifies the title that an executable belongs to
+#[derive(Debug, Clone)]
+pub struct ExecutionId {
+    pub media_id: u32,
+    pub version: u32,
+    pub base_version: u32,
+    pub title_id: u32,
+    pub platform: u8,
+    pub executable_type: u8,
+    pub disc_number: u8,
+    pub disc_count: u8,
+    pub savegame_id: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct XexHeader {
+    module_flags: ModuleFlags,
+    pe_data_offset: u32,
+    security_info_offset: u32,
+    optional_headers: Vec<OptionalHeader>,
+    image_size: u32,
+    execution_id: Option<ExecutionId>,
+    original_pe_name: Option<String>,
+    file_format: Option<FileFormatInfo>,
+}
+
+impl XexHeader {
+    /// Parses the header of the XEX2 file at the start of `data`. `data` may
+    /// extend past the end of the file.
+    pub fn parse(data: &[u8]) -> Result<XexHeader, FormatError> {
+        if data.len() < HEADER_SIZE {
+            return Err(FormatError::InvalidHeader {
+                format: "XEX2",
+                reason: "file is too small",
+            });
+        }
+
+        let mut cursor = Cursor::new(data);
+
+        let mut magic = [0u8; 4];
+        cursor.read_exact(&mut magic)?;
+        if &magic != XEX2_MAGIC {
+            return Err(FormatError::InvalidMagic {
+                format: "XEX2",
+                magic: u32::from_be_bytes(magic),
+            });
+        }
+
+        let module_flags = ModuleFlags::from_bits_truncate(cursor.read_u32::<BigEndian>()?);
+        let pe_data_offset = cursor.read_u32::<BigEndian>()?;
+        let _reserved = cursor.read_u32::<BigEndian>()?;
+        let security_info_offset = cursor.read_u32::<BigEndian>()?;
+        let optional_header_count = cursor.read_u32::<BigEndian>()?;
+
+        if optional_header_count > MAX_OPTIONAL_HEADERS {
+            return Err(FormatError::InvalidHeader {
+                format: "XEX2",
+                reason: "too many optional headers",
+            });
+        }
+
+        if (pe_data_offset as usize) < HEADER_SIZE
+            || security_info_offset >= pe_data_offset
+            || (security_info_offset as usize) < HEADER_SIZE
+        {
+            return Err(FormatError::InvalidHeader {
+                format: "XEX2",
+                reason: "header offsets are out of order",
+            });
+        }
+
+        let optional_headers = (0..optional_header_count)
+            .map(|_| {
+                Ok(OptionalHeader {
+                    key: cursor.read_u32::<BigEndian>()?,
+                    value: cursor.read_u32::<BigEndian>()?,
+                })
+            })
+            .collect::<Result<Vec<_>, FormatError>>()?;
+
+        // The security info starts with its own size followed by the size of
+        // the loaded image
+        cursor.seek(SeekFrom::Start(security_info_offset as u64 + 0x4))?;
+        let image_size = cursor.read_u32::<BigEndian>()?;
+
+        let mut header = XexHeader {
+            module_flags,
+            pe_data_offset,
+            security_info_offset,
+            optional_headers,
+            image_size,
+            execution_id: None,
+            original_pe_name: None,
+            file_format: None,
+        };
+
+        if let Some(offset) = header.optional_header_offset(KEY_EXECUTION_ID) {
+            cursor.seek(SeekFrom::Start(offset))?;
+            header.execution_id = Some(ExecutionId {
+                media_id: cursor.read_u32::<BigEndian>()?,
+                version: cursor.read_u32::<BigEndian>()?,
+                base_version: cursor.read_u32::<BigEndian>()?,
+                title_id: cursor.read_u32::<BigEndian>()?,
+                platform: cursor.read_u8()?,
+                executable_type: cursor.read_u8()?,
+                disc_number: cursor.read_u8()?,
+                disc_count: cursor.read_u8()?,
+                savegame_id: cursor.read_u32::<BigEndian>()?,
+            });
+        }
+
+        if let Some(offset) = header.optional_header_offset(KEY_ORIGINAL_PE_NAME) {
+            cursor.seek(SeekFrom::Start(offset))?;
+            // The size includes the size field itself
+            let size = cursor.read_u32::<BigEndian>()?.saturating_sub(4) as usize;
+            let start = offset as usize + 4;
+            let name_bytes = data.get(start..start + size).unwrap_or_default();
+            let name_len = name_bytes
+                .iter()
+                .position(|b| *b == 0)
+                .unwrap_or(name_bytes.len());
+            header.original_pe_name =
+                Some(String::from_utf8_lossy(&name_bytes[..name_len]).into_owned());
+        }
+
+        if let Some(offset) = header.optional_header_offset(KEY_FILE_FORMAT_INFO) {
+            cursor.seek(SeekFrom::Start(offset))?;
+            header.file_format = Some(FileFormatInfo::parse(
+                &mut cursor,
+                (pe_data_offset as u64).saturating_sub(offset),
+            )?);
+        }
+
+        Ok(header)
+    }
+
+    pub fn module_flags(&self) -> ModuleFlags {
+        self.module_flags
+    }
+
+    /// Offset of the basefile data from the start of the file
+    pub fn pe_data_offset(&self) -> u32 {
+        self.pe_data_offset
+    }
+
+    pub fn security_info_offset(&self) -> u32 {
+        self.security_info_offset
+    }
+
+    pub fn optional_headers(&self) -> &[OptionalHeader] {
+        &self.optional_headers
+    }
+
+    pub fn optional_header(&self, key: u32) -> Option<&OptionalHeader> {
+        self.optional_headers.iter().find(|h| h.key == key)
+    }
+
+    /// Size of the basefile once it has been loaded into memory
+    pub fn image_size(&self) -> u32 {
+        self.image_size
+    }
+
+    pub fn execution_id(&self) -> Option<&ExecutionId> {
+        self.execution_id.as_ref()
+    }
+
+    pub fn title_id(&self) -> Option<u32> {
+        self.execution_id.as_ref().map(|id| id.title_id)
+    }
+
+    pub fn original_pe_name(&self) -> Option<&str> {
+        self.original_pe_name.as_deref()
+    }
+
+    pub fn file_format(&self) -> Option<&FileFormatInfo> {
+        self.file_format.as_ref()
+    }
+
+    /// Works out the size of the whole XEX2 file at the start of `data`.
+    ///
+    /// The size is exact for files whose basefile is uncompressed, basic
+    /// compressed, or unencrypted LZX compressed. The block chain of encrypted
+    /// LZX compressed files can't be walked, so the size of the loaded image is
+    /// used as an upper bound instead (compressed data is always smaller).
+    pub fn file_size(&self, data: &[u8]) -> Option<u64> {
+        let pe_data_offset = self.pe_data_offset as u64;
+        let format = self.file_format.as_ref()?;
+
+        let data_size = match (&format.compression, format.encryption) {
+            (Compression::None, _) => self.image_size as u64,
+            (Compression::Basic(blocks), _) => {
+                blocks.iter().map(|b| b.data_size as u64).sum::<u64>()
+            }
+            (
+                Compression::Normal {
+                    first_block_size, ..
+                },
+                EncryptionType::None,
+            ) => compressed_data_size(data, pe_data_offset, *first_block_size)?,
+            (Compression::Normal { .. }, _) => self.image_size as u64,
+            _ => return None,
+        };
+
+        // Encrypted data is padded out to the AES block size
+        let data_size = match format.encryption {
+            EncryptionType::None => data_size,
+            _ => (data_size + 0xF) & !0xF,
+        };
+
+        Some(pe_data_offset + data_size)
+    }
+
+    /// Suggested name for the file based off of its title ID and original
+    /// PE name
+    pub fn suggested_name(&self) -> Option<String> {
+        let pe_name = self.original_pe_name.as_ref().map(|name| {
+            // Strip the extension since the file will be written as .xex
+            match name.rfind('.') {
+                Some(idx) if idx > 0 => &name[..idx],
+                _ => name.as_str(),
+            }
+        });
+
+        match (self.title_id(), pe_name) {
+            (Some(title_id), Some(name)) => Some(format!("{:08X}_{}", title_id, name)),
+            (Some(title_id), None) => Some(format!("{:08X}", title_id)),
+            (None, Some(name)) => Some(name.to_owned()),
+            (None, None) => None,
+        }
+    }
+
+    /// Returns the file offset of the data for an optional header that isn't
+    /// stored inline
+    fn optional_header_offset(&self, key: u32) -> Option<u64> {
+        self.optional_header(key)
+            .filter(|h| !h.is_inline())
+            .map(|h| h.value as u64)
+    }
+}
+
+/// Walks the chain of LZX compressed blocks starting at `pe_data_offset` and
+/// returns their total size. Each block starts with the size and hash of the
+/// block that follows it.
+fn compressed_data_size(data: &[u8], pe_data_offset: u64, first_block_size: u32) -> Option<u64> {
+    let mut total = 0u64;
+    let mut block_size = first_block_size as u64;
+
+    while block_size != 0 {
+        let block_start = pe_data_offset + total;
+        if block_start + block_size > data.len() as u64 || block_size < BLOCK_HASH_SIZE + 4 {
+            return None;
+        }
+
+        total += block_size;
+
+        let mut cursor = Cursor::new(data);
+        cursor.set_position(block_start);
+        block_size = cursor.read_u32::<BigEndian>().ok()? as u64;
+    }
+
+    Some(total)
+}